@@ -0,0 +1,122 @@
+//! Dispatch loop throughput, run with `cargo bench`.
+//!
+//! The compiler folds literal-only expressions into a single constant, so
+//! these workloads build their chunks by hand to keep every instruction
+//! around for the VM to execute.
+
+use clox::chunk::{Chunk, OpCode, Position};
+use clox::register::lower;
+use clox::value::Value;
+use clox::vm::{Backend, RunStatus, Vm};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+/// Source position every hand-built instruction is attributed to.
+const AT: Position = Position {
+    line: 1,
+    column: 1,
+    source_offset: 0,
+};
+
+/// `1 + 2 + 3 + ...`: one constant load and one add per term.
+fn arithmetic(terms: usize) -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_constant(Value::number(0.0), AT);
+    for i in 0..terms {
+        chunk.write_constant(Value::number((i % 200) as f64), AT);
+        chunk.write_chunk(
+            if i % 2 == 0 {
+                OpCode::OpAdd
+            } else {
+                OpCode::OpSubtract
+            },
+            AT,
+        );
+    }
+    chunk.write_chunk(OpCode::OpReturn, AT);
+    chunk
+}
+
+/// `!(a < b) == true`, repeated: comparisons, nots and equality on booleans.
+fn comparisons(terms: usize) -> Chunk {
+    let mut chunk = Chunk::new();
+    chunk.write_chunk(OpCode::OpTrue, AT);
+    for i in 0..terms {
+        chunk.write_constant(Value::number((i % 100) as f64), AT);
+        chunk.write_constant(Value::number(50.0), AT);
+        chunk.write_chunk(OpCode::OpLess, AT);
+        chunk.write_chunk(OpCode::OpNot, AT);
+        chunk.write_chunk(OpCode::OpEqual, AT);
+    }
+    chunk.write_chunk(OpCode::OpReturn, AT);
+    chunk
+}
+
+/// Push every term before reducing them, so the value stack grows deep.
+fn deep_stack(terms: usize) -> Chunk {
+    let mut chunk = Chunk::new();
+    for i in 0..terms {
+        chunk.write_constant(Value::number((i % 200) as f64), AT);
+        chunk.write_chunk(OpCode::OpNegate, AT);
+    }
+    for _ in 1..terms {
+        chunk.write_chunk(OpCode::OpMultiply, AT);
+    }
+    chunk.write_chunk(OpCode::OpReturn, AT);
+    chunk
+}
+
+fn instruction_count(chunk: &Chunk) -> usize {
+    let mut count = 0;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op_code = OpCode::from_byte(chunk.code[offset]).unwrap();
+        offset += 1 + op_code.operand_len();
+        count += 1;
+    }
+    count
+}
+
+fn bench(name: &str, chunk: Chunk, backend: Backend) {
+    let instructions = instruction_count(&chunk);
+    let mut vm = Vm::new();
+    vm.set_backend(backend);
+    let mut best = Duration::MAX;
+    for _ in 0..ITERATIONS {
+        vm.load_chunk(chunk.clone()).unwrap();
+        let start = Instant::now();
+        let status = vm.run_for(usize::MAX);
+        best = best.min(start.elapsed());
+        assert!(matches!(status, RunStatus::Finished(_)));
+    }
+
+    println!(
+        "{:<18} {:>9} instructions  {:>8.2} ms  {:>6.2} ns/instruction",
+        name,
+        instructions,
+        best.as_secs_f64() * 1e3,
+        best.as_secs_f64() * 1e9 / instructions as f64
+    );
+}
+
+fn main() {
+    let workloads = [
+        ("arithmetic", arithmetic(1_000_000)),
+        ("comparisons", comparisons(400_000)),
+        ("deep_stack", deep_stack(500_000)),
+    ];
+    for (name, chunk) in &workloads {
+        bench(name, chunk.clone(), Backend::Stack);
+    }
+
+    // Times are per stack instruction in both tables, so the two compare
+    // directly even though the register code executes fewer instructions.
+    println!();
+    for (name, chunk) in &workloads {
+        match lower(chunk) {
+            Ok(_) => bench(&format!("{} (reg)", name), chunk.clone(), Backend::Register),
+            Err(err) => println!("{:<18} skipped: {}", format!("{} (reg)", name), err),
+        }
+    }
+}
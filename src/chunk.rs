@@ -1,8 +1,20 @@
-use crate::value::Value;
+use crate::serialize::{write_str, write_u32, write_u8, write_value, DecodeError, Reader};
+use crate::value::{Value, ValueKey};
+use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+const MAGIC: &[u8] = b"LOXC";
+const VERSION: u8 = 3;
+
+/// Largest constant index an `OpConstantLong` operand can address.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum OpCode {
-    OpConstant { index: usize },
+    /// Push a constant; one-byte constant index operand.
+    OpConstant,
+    /// Push a constant; three-byte little-endian constant index operand.
+    OpConstantLong,
     OpNil,
     OpTrue,
     OpFalse,
@@ -16,31 +28,456 @@ pub enum OpCode {
     OpEqual,
     OpGreater,
     OpLess,
+    OpNotEqual,
+    /// Computes `!(a < b)`, matching the `OpLess, OpNot` pair it replaces for NaN.
+    OpGreaterEqual,
+    /// Computes `!(a > b)`, matching the `OpGreater, OpNot` pair it replaces for NaN.
+    OpLessEqual,
+    /// `OpAdd` quickened by the VM after seeing two numbers; never emitted by
+    /// the compiler. Reverts to `OpAdd` if its operands stop being numbers.
+    OpAddNumber,
+    /// `OpConstant` followed by `OpAdd`; one-byte constant index operand.
+    OpConstantAdd,
+}
+
+impl OpCode {
+    pub(crate) const ALL: [OpCode; 20] = [
+        OpCode::OpConstant,
+        OpCode::OpConstantLong,
+        OpCode::OpNil,
+        OpCode::OpTrue,
+        OpCode::OpFalse,
+        OpCode::OpNegate,
+        OpCode::OpReturn,
+        OpCode::OpAdd,
+        OpCode::OpSubtract,
+        OpCode::OpMultiply,
+        OpCode::OpDivide,
+        OpCode::OpNot,
+        OpCode::OpEqual,
+        OpCode::OpGreater,
+        OpCode::OpLess,
+        OpCode::OpNotEqual,
+        OpCode::OpGreaterEqual,
+        OpCode::OpLessEqual,
+        OpCode::OpAddNumber,
+        OpCode::OpConstantAdd,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    /// Number of operand bytes that follow the opcode in the bytecode.
+    pub fn operand_len(self) -> usize {
+        match self {
+            OpCode::OpConstant | OpCode::OpConstantAdd => 1,
+            OpCode::OpConstantLong => 3,
+            _ => 0,
+        }
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(op_code: OpCode) -> Self {
+        op_code as u8
+    }
+}
+
+/// The token in the source that a piece of bytecode was emitted for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    /// Byte offset of the token's first character in the source.
+    pub source_offset: usize,
+}
+
+/// A run of consecutive bytecode bytes compiled from the same source line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineRun {
+    /// Offset of the first byte in the run; it extends to the next run's start.
+    pub start: u32,
+    pub line: u32,
+}
+
+/// A run of consecutive bytecode bytes whose columns are counted from the
+/// same `base`: a byte's column is its source offset minus `base`, modulo
+/// 2^32.
+///
+/// On a line of ASCII text `base` is one before the line's first byte, so a
+/// line needs a single run. Columns count characters, so each multi-byte
+/// character moves `base` along by its extra bytes for the tokens after it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnRun {
+    /// Offset of the first byte in the run; it extends to the next run's start.
+    pub start: u32,
+    pub base: u32,
 }
 
 #[derive(Clone, Debug)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
-    pub lines: Vec<usize>,
+    /// Name of the source the chunk was compiled from, for error messages.
+    pub file: String,
+    pub code: Vec<u8>,
+    pub lines: Vec<LineRun>,
+    pub columns: Vec<ColumnRun>,
+    /// Source offset of the token each byte of `code` was emitted for.
+    pub source_offsets: Vec<u32>,
     pub constants: Vec<Value>,
+    /// Where each constant was first added, so `add_constant` can reuse it.
+    /// Entries are checked against `constants` before use, so a pool that
+    /// was truncated or pushed to directly only loses deduplication.
+    constant_indexes: HashMap<ValueKey, usize>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Self {
+        Self::with_constants(vec![])
+    }
+
+    /// An empty chunk whose constant pool starts out as `constants`.
+    pub fn with_constants(constants: Vec<Value>) -> Self {
+        let mut constant_indexes = HashMap::new();
+        for (index, constant) in constants.iter().enumerate() {
+            constant_indexes.entry(constant.key()).or_insert(index);
+        }
+
         Self {
+            file: "<script>".to_string(),
             code: vec![],
             lines: vec![],
-            constants: vec![],
+            columns: vec![],
+            source_offsets: vec![],
+            constants,
+            constant_indexes,
+        }
+    }
+
+    pub fn write_chunk(&mut self, byte: impl Into<u8>, position: Position) {
+        let start = self.code.len() as u32;
+        let line = position.line as u32;
+        if self.lines.last().map(|run| run.line) != Some(line) {
+            self.lines.push(LineRun { start, line });
+        }
+        let source_offset = position.source_offset as u32;
+        let base = source_offset.wrapping_sub(position.column as u32);
+        if self.columns.last().map(|run| run.base) != Some(base) {
+            self.columns.push(ColumnRun { start, base });
         }
+        self.source_offsets.push(source_offset);
+        self.code.push(byte.into());
     }
 
-    pub fn write_chunk(&mut self, op_code: OpCode, line: usize) {
-        self.code.push(op_code);
-        self.lines.push(line);
+    /// Drop all code from offset `len` onwards, along with its line info.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.source_offsets.truncate(len);
+        let runs = self.lines.partition_point(|run| (run.start as usize) < len);
+        self.lines.truncate(runs);
+        let runs = self
+            .columns
+            .partition_point(|run| (run.start as usize) < len);
+        self.columns.truncate(runs);
     }
 
+    /// Source line of the bytecode byte at `offset`.
+    pub fn get_line(&self, offset: usize) -> usize {
+        self.get_position(offset).line
+    }
+
+    /// Source column of the bytecode byte at `offset`.
+    pub fn get_column(&self, offset: usize) -> usize {
+        self.get_position(offset).column
+    }
+
+    /// Source position, including the token's byte offset, of the bytecode
+    /// byte at `offset`.
+    pub fn get_position(&self, offset: usize) -> Position {
+        let line = self
+            .lines
+            .partition_point(|run| run.start as usize <= offset);
+        let column = self
+            .columns
+            .partition_point(|run| run.start as usize <= offset);
+        let source_offset = self.source_offsets[offset];
+        Position {
+            line: self.lines[line - 1].line as usize,
+            column: source_offset.wrapping_sub(self.columns[column - 1].base) as usize,
+            source_offset: source_offset as usize,
+        }
+    }
+
+    /// Add `value` to the constant pool, reusing an identical existing constant.
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = value.key();
+        if let Some(&index) = self.constant_indexes.get(&key) {
+            if self.constants.get(index).map(Value::key) == Some(key) {
+                return index;
+            }
+        }
+
         self.constants.push(value);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        self.constant_indexes.insert(key, index);
+        index
+    }
+
+    /// Add `value` to the constant pool and emit the instruction that loads
+    /// it, switching to `OpConstantLong` once the index no longer fits a byte.
+    pub fn write_constant(&mut self, value: Value, position: Position) {
+        let index = self.add_constant(value);
+        self.write_constant_index(index, position);
+    }
+
+    /// Emit the instruction that loads the constant already stored at `index`.
+    pub fn write_constant_index(&mut self, index: usize, position: Position) {
+        if index <= u8::MAX as usize {
+            self.write_chunk(OpCode::OpConstant, position);
+            self.write_chunk(index as u8, position);
+        } else {
+            self.write_chunk(OpCode::OpConstantLong, position);
+            for byte in &(index as u32).to_le_bytes()[..3] {
+                self.write_chunk(*byte, position);
+            }
+        }
+    }
+
+    /// Decode the constant index operand of the constant instruction at `offset`.
+    pub fn read_constant_index(&self, offset: usize) -> usize {
+        match OpCode::from_byte(self.code[offset]) {
+            Some(OpCode::OpConstantLong) => {
+                let operand = &self.code[offset + 1..offset + 4];
+                u32::from_le_bytes([operand[0], operand[1], operand[2], 0]) as usize
+            }
+            _ => self.code[offset + 1] as usize,
+        }
+    }
+
+    /// Serialize the chunk into the versioned `.loxc` bytecode image.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        write_u8(&mut out, VERSION);
+        write_str(&mut out, &self.file);
+
+        write_u32(&mut out, self.code.len() as u32);
+        out.extend_from_slice(&self.code);
+
+        write_u32(&mut out, self.lines.len() as u32);
+        for run in &self.lines {
+            write_u32(&mut out, run.start);
+            write_u32(&mut out, run.line);
+        }
+        write_u32(&mut out, self.columns.len() as u32);
+        for run in &self.columns {
+            write_u32(&mut out, run.start);
+            write_u32(&mut out, run.base);
+        }
+        for source_offset in &self.source_offsets {
+            write_u32(&mut out, *source_offset);
+        }
+
+        write_u32(&mut out, self.constants.len() as u32);
+        for constant in &self.constants {
+            write_value(&mut out, constant);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        reader.expect_header(MAGIC, VERSION)?;
+        let file = reader.read_string()?;
+
+        let code_len = reader.read_u32()? as usize;
+        let code = reader.read_bytes(code_len)?.to_vec();
+
+        let run_count = reader.read_u32()?;
+        let mut lines = vec![];
+        for _ in 0..run_count {
+            lines.push(LineRun {
+                start: reader.read_u32()?,
+                line: reader.read_u32()?,
+            });
+        }
+        let run_count = reader.read_u32()?;
+        let mut columns = vec![];
+        for _ in 0..run_count {
+            columns.push(ColumnRun {
+                start: reader.read_u32()?,
+                base: reader.read_u32()?,
+            });
+        }
+        // `source_offsets` is as long as `code`, which is already bounded by
+        // the image size.
+        let mut source_offsets = Vec::with_capacity(code_len);
+        for _ in 0..code_len {
+            source_offsets.push(reader.read_u32()?);
+        }
+
+        let constant_count = reader.read_u32()?;
+        let mut constants = vec![];
+        for _ in 0..constant_count {
+            constants.push(reader.read_value()?);
+        }
+
+        Ok(Self {
+            file,
+            code,
+            lines,
+            columns,
+            source_offsets,
+            ..Self::with_constants(constants)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_decode_to_their_discriminant() {
+        for (index, op_code) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*op_code as usize, index);
+            assert_eq!(OpCode::from_byte(index as u8), Some(*op_code));
+        }
+        assert_eq!(OpCode::from_byte(OpCode::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn repeated_constant_is_stored_once() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Value::bool(true)), 1);
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 0);
+        assert_eq!(chunk.constants.len(), 2);
+    }
+
+    #[test]
+    fn signed_zeros_are_distinct_constants() {
+        let mut chunk = Chunk::new();
+        let zero = chunk.add_constant(Value::number(0.0));
+        let negative_zero = chunk.add_constant(Value::number(-0.0));
+        assert_ne!(zero, negative_zero);
+        assert_eq!(chunk.add_constant(Value::number(-0.0)), negative_zero);
+    }
+
+    #[test]
+    fn nan_constant_is_reused() {
+        let mut chunk = Chunk::new();
+        let nan = chunk.add_constant(Value::number(f64::NAN));
+        assert_eq!(chunk.add_constant(Value::number(f64::NAN)), nan);
+        assert_eq!(chunk.constants.len(), 1);
+    }
+
+    #[test]
+    fn truncated_constant_is_not_reused() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 0);
+        chunk.constants.truncate(0);
+        assert_eq!(chunk.add_constant(Value::number(2.0)), 0);
+
+        // The entry for 1 still names index 0, which now holds 2.
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 1);
+        assert_eq!(
+            chunk.constants,
+            vec![Value::number(2.0), Value::number(1.0)]
+        );
+    }
+
+    /// A chunk with a file name, multi-line positions and every kind of value.
+    fn sample_chunk() -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.file = "sample.lox".to_string();
+        let position = |line, column, source_offset| Position {
+            line,
+            column,
+            source_offset,
+        };
+        chunk.write_constant(Value::number(1.5), position(1, 1, 0));
+        chunk.write_chunk(OpCode::OpNil, position(1, 5, 4));
+        chunk.write_chunk(OpCode::OpEqual, position(2, 3, 8));
+        // After a two-byte character on the same line.
+        chunk.write_chunk(OpCode::OpNot, position(2, 5, 11));
+        chunk.write_chunk(OpCode::OpReturn, position(3, 1, 14));
+        chunk.add_constant(Value::bool(false));
+        chunk.add_constant(Value::nil());
+        chunk
+    }
+
+    #[test]
+    fn image_round_trips() {
+        let chunk = sample_chunk();
+        let decoded = Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+        assert_eq!(decoded.file, chunk.file);
+        assert_eq!(decoded.code, chunk.code);
+        assert_eq!(decoded.lines, chunk.lines);
+        assert_eq!(decoded.columns, chunk.columns);
+        assert_eq!(decoded.source_offsets, chunk.source_offsets);
+        assert_eq!(decoded.constants, chunk.constants);
+        for offset in 0..chunk.code.len() {
+            assert_eq!(decoded.get_position(offset), chunk.get_position(offset));
+        }
+    }
+
+    #[test]
+    fn positions_are_recovered_from_the_tables() {
+        let chunk = sample_chunk();
+        let positions: Vec<(usize, usize, usize)> = (0..chunk.code.len())
+            .map(|offset| chunk.get_position(offset))
+            .map(|position| (position.line, position.column, position.source_offset))
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                (1, 1, 0),
+                (1, 1, 0),
+                (1, 5, 4),
+                (2, 3, 8),
+                (2, 5, 11),
+                (3, 1, 14)
+            ]
+        );
+    }
+
+    #[test]
+    fn line_table_has_one_run_per_line() {
+        let line = "-true + -true + -true + -true + -true + -true";
+        let source = format!("{}\n+ {}\n+ {}", line, line, line);
+        let chunk = crate::compile::Parser::new(&source).compile().unwrap();
+
+        assert!(chunk.code.len() > 50);
+        let lines: Vec<u32> = chunk.lines.iter().map(|run| run.line).collect();
+        assert_eq!(lines, [1, 2, 3]);
+        assert_eq!(chunk.columns.len(), 3);
+        // The last `OpAdd` is the last `+` on the third line.
+        let column = "+ ".len() + line.rfind('+').unwrap() + 1;
+        assert_eq!(chunk.get_column(chunk.code.len() - 2), column);
+    }
+
+    #[test]
+    fn truncated_image_is_rejected() {
+        let bytes = sample_chunk().to_bytes();
+        let header_len = MAGIC.len() + 1;
+        assert_eq!(
+            Chunk::from_bytes(&bytes[..MAGIC.len() - 1]).unwrap_err(),
+            DecodeError::BadMagic
+        );
+        for len in header_len..bytes.len() {
+            assert_eq!(
+                Chunk::from_bytes(&bytes[..len]).unwrap_err(),
+                DecodeError::UnexpectedEof,
+                "{} of {} bytes",
+                len,
+                bytes.len()
+            );
+        }
     }
 }
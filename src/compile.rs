@@ -1,19 +1,29 @@
-use crate::chunk::{Chunk, OpCode};
-use crate::scanner::{Scanner, Token, TokenType};
+use crate::chunk::{Chunk, OpCode, Position, MAX_CONSTANTS};
+use crate::fold::{fold_binary, fold_unary};
+use crate::peephole::optimize;
+use crate::register::{lower, RegisterChunk};
+use crate::scanner::{parse_number, Scanner, Span, Token, TokenType};
 use crate::value::Value;
-use std::collections::HashMap;
-use std::str::FromStr;
+use crate::verify::VerifyError;
 
-pub struct Parser {
-    previous: Option<Token>,
-    current: Option<Token>,
-    scanner: Scanner,
+pub struct Parser<'src> {
+    previous: Token<'src>,
+    current: Token<'src>,
+    scanner: Scanner<'src>,
     had_error: bool,
     chunk: Chunk,
-    parse_rules: HashMap<TokenType, ParseRule>,
+    left_operand: Mark,
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq)]
+/// A point in the chunk being compiled. Constant folding rewinds the chunk
+/// to one of these before emitting the folded value in place of its operands.
+#[derive(Clone, Copy, Debug, Default)]
+struct Mark {
+    code_len: usize,
+    constants_len: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialOrd, PartialEq)]
 enum Precedence {
     None,
     Assignment,
@@ -46,380 +56,141 @@ impl Precedence {
     }
 }
 
-#[derive(Debug, Clone)]
-enum ParseFn {
-    Unary,
-    Grouping,
-    Binary,
-    Number,
-    Literal,
-}
+/// Compiles the expression whose first token was just consumed.
+type ParseFn<'src> = fn(&mut Parser<'src>);
 
-#[derive(Debug, Clone)]
-struct ParseRule {
-    prefix: Option<ParseFn>,
-    infix: Option<ParseFn>,
+#[derive(Clone, Copy)]
+struct ParseRule<'src> {
+    prefix: Option<ParseFn<'src>>,
+    infix: Option<ParseFn<'src>>,
     precedence: Precedence,
 }
 
-impl Parser {
-    pub fn new(source: &str) -> Self {
+impl<'src> ParseRule<'src> {
+    const fn new(
+        prefix: Option<ParseFn<'src>>,
+        infix: Option<ParseFn<'src>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+impl<'src> Parser<'src> {
+    pub fn new(source: &'src str) -> Self {
+        // Placeholder until `advance` reads the first real token.
+        let start = Token {
+            t_type: TokenType::Eof,
+            token: "",
+            line: 1,
+            column: 1,
+            span: Span::default(),
+        };
         Self {
-            previous: None,
-            current: None,
+            previous: start,
+            current: start,
             scanner: Scanner::new(source),
             had_error: false,
             chunk: Chunk::new(),
-            parse_rules: Self::build_parse_rules(),
+            left_operand: Mark::default(),
         }
     }
 
-    fn build_parse_rules() -> HashMap<TokenType, ParseRule> {
-        HashMap::from([
-            (
-                TokenType::LeftParen,
-                ParseRule {
-                    prefix: Some(ParseFn::Grouping),
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::RightParen,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::LeftBrace,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::RightBrace,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Comma,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Dot,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Minus,
-                ParseRule {
-                    prefix: Some(ParseFn::Unary),
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Term,
-                },
-            ),
-            (
-                TokenType::Plus,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Term,
-                },
-            ),
-            (
-                TokenType::Semicolon,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Slash,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Factor,
-                },
-            ),
-            (
-                TokenType::Star,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Factor,
-                },
-            ),
-            (
-                TokenType::Bang,
-                ParseRule {
-                    prefix: Some(ParseFn::Unary),
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::BangEqual,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Equality,
-                },
-            ),
-            (
-                TokenType::Equal,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::EqualEqual,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Equality,
-                },
-            ),
-            (
-                TokenType::Greater,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Comparison,
-                },
-            ),
-            (
-                TokenType::GreaterEqual,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Comparison,
-                },
-            ),
-            (
-                TokenType::Less,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Comparison,
-                },
-            ),
-            (
-                TokenType::LessEqual,
-                ParseRule {
-                    prefix: None,
-                    infix: Some(ParseFn::Binary),
-                    precedence: Precedence::Comparison,
-                },
-            ),
-            (
-                TokenType::Identifier,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::String,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Number,
-                ParseRule {
-                    prefix: Some(ParseFn::Number),
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::And,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Class,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Else,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::False,
-                ParseRule {
-                    prefix: Some(ParseFn::Literal),
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::For,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Fun,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::If,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Nil,
-                ParseRule {
-                    prefix: Some(ParseFn::Literal),
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Or,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Print,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Return,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Super,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::This,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::True,
-                ParseRule {
-                    prefix: Some(ParseFn::Literal),
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Var,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::While,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Error,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-            (
-                TokenType::Eof,
-                ParseRule {
-                    prefix: None,
-                    infix: None,
-                    precedence: Precedence::None,
-                },
-            ),
-        ])
-    }
-
-    fn get_rule(&self, t_type: &TokenType) -> &ParseRule {
-        self.parse_rules.get(t_type).unwrap()
-    }
-
-    fn dispatch_parse_fn(&mut self, parse_fn: &ParseFn) {
-        match parse_fn {
-            ParseFn::Unary => self.unary(),
-            ParseFn::Grouping => self.grouping(),
-            ParseFn::Binary => self.binary(),
-            ParseFn::Number => self.number(),
-            ParseFn::Literal => self.literal(),
-        }
+    /// Parse rules indexed by `TokenType` discriminant, so rows must stay in
+    /// the order the token types are declared. `Eof` is the last one, which
+    /// makes adding a token type without a row a compile error.
+    const RULES: [ParseRule<'src>; TokenType::Eof as usize + 1] = {
+        use Precedence as P;
+        let none = ParseRule::new(None, None, P::None);
+        [
+            ParseRule::new(Some(Self::grouping), None, P::None), // LeftParen
+            none,                                                // RightParen
+            none,                                                // LeftBrace
+            none,                                                // RightBrace
+            none,                                                // Comma
+            none,                                                // Dot
+            ParseRule::new(Some(Self::unary), Some(Self::binary), P::Term), // Minus
+            ParseRule::new(None, Some(Self::binary), P::Term),   // Plus
+            none,                                                // Semicolon
+            ParseRule::new(None, Some(Self::binary), P::Factor), // Slash
+            ParseRule::new(None, Some(Self::binary), P::Factor), // Star
+            ParseRule::new(Some(Self::unary), None, P::None),    // Bang
+            ParseRule::new(None, Some(Self::binary), P::Equality), // BangEqual
+            none,                                                // Equal
+            ParseRule::new(None, Some(Self::binary), P::Equality), // EqualEqual
+            ParseRule::new(None, Some(Self::binary), P::Comparison), // Greater
+            ParseRule::new(None, Some(Self::binary), P::Comparison), // GreaterEqual
+            ParseRule::new(None, Some(Self::binary), P::Comparison), // Less
+            ParseRule::new(None, Some(Self::binary), P::Comparison), // LessEqual
+            none,                                                // Identifier
+            none,                                                // String
+            ParseRule::new(Some(Self::number), None, P::None),   // Number
+            none,                                                // And
+            none,                                                // Class
+            none,                                                // Else
+            ParseRule::new(Some(Self::literal), None, P::None),  // False
+            none,                                                // For
+            none,                                                // Fun
+            none,                                                // If
+            ParseRule::new(Some(Self::literal), None, P::None),  // Nil
+            none,                                                // Or
+            none,                                                // Print
+            none,                                                // Return
+            none,                                                // Super
+            none,                                                // This
+            ParseRule::new(Some(Self::literal), None, P::None),  // True
+            none,                                                // Var
+            none,                                                // While
+            none,                                                // Error
+            none,                                                // Eof
+        ]
+    };
+
+    /// Name the source being compiled, for error messages and the chunk's
+    /// debug info.
+    pub fn with_file(mut self, file: &str) -> Self {
+        self.chunk.file = file.to_string();
+        self
+    }
+
+    fn get_rule(t_type: TokenType) -> ParseRule<'src> {
+        Self::RULES[t_type as usize]
     }
 
-    pub fn compile(&mut self) -> Chunk {
+    pub fn compile(&mut self) -> Option<Chunk> {
         self.advance();
         self.expression();
         self.consume(&TokenType::Eof, "Expect end of expression.");
         self.end_compile();
 
-        self.chunk.clone()
+        if self.had_error {
+            None
+        } else {
+            Some(optimize(&self.chunk))
+        }
+    }
+
+    /// Compile for the register backend: the same code as [`Parser::compile`],
+    /// lowered to three-address form.
+    pub fn compile_registers(&mut self) -> Option<RegisterChunk> {
+        let chunk = self.compile()?;
+        match lower(&chunk) {
+            Ok(lowered) => Some(lowered),
+            Err(VerifyError::TooManyRegisters { offset }) => {
+                let position = chunk.get_position(offset);
+                eprintln!(
+                    "{}:{}:{}: Error: Expression needs too many registers.",
+                    chunk.file, position.line, position.column
+                );
+                self.had_error = true;
+                None
+            }
+            Err(err) => unreachable!("compiler emitted invalid bytecode: {}", err),
+        }
     }
 
     fn end_compile(&mut self) {
@@ -427,7 +198,7 @@ impl Parser {
     }
 
     fn consume(&mut self, token_type: &TokenType, message: &str) {
-        if &self.current.as_ref().unwrap().t_type == token_type {
+        if &self.current.t_type == token_type {
             self.advance();
         } else {
             self.error_at_current(message);
@@ -435,16 +206,15 @@ impl Parser {
     }
 
     fn advance(&mut self) {
-        self.previous = self.current.clone();
+        self.previous = self.current;
 
         loop {
-            self.current = Some(self.scanner.scan_token());
-            if self.current.as_ref().unwrap().t_type != TokenType::Error {
+            self.current = self.scanner.scan_token();
+            if self.current.t_type != TokenType::Error {
                 break;
             }
 
-            let current = &self.current.clone().unwrap();
-            self.error_at_current(&current.token);
+            self.error_at_current(self.current.token);
         }
     }
 
@@ -453,13 +223,14 @@ impl Parser {
     }
 
     fn number(&mut self) {
-        let token = &self.previous.clone().unwrap();
-        let value = f64::from_str(&token.token).unwrap();
-        self.emit_constant(Value::Number(value));
+        match parse_number(self.previous.token) {
+            Some(value) => self.emit_constant(Value::number(value)),
+            None => self.error("Invalid number literal."),
+        }
     }
 
     fn literal(&mut self) {
-        match self.previous.clone().unwrap().t_type {
+        match self.previous.t_type {
             TokenType::False => self.emit_byte(OpCode::OpFalse),
             TokenType::Nil => self.emit_byte(OpCode::OpNil),
             TokenType::True => self.emit_byte(OpCode::OpTrue),
@@ -473,55 +244,140 @@ impl Parser {
     }
 
     fn unary(&mut self) {
-        let operator_type = &self.previous.clone().unwrap().t_type;
+        let operator_type = self.previous.t_type;
+        // Runtime errors in the operator should point at it, not at the last
+        // token of its operand.
+        let operator = self.previous.position();
 
+        let operand = self.mark();
         self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::OpNot),
-            TokenType::Minus => self.emit_byte(OpCode::OpNegate),
+        let op_code = match operator_type {
+            TokenType::Bang => OpCode::OpNot,
+            TokenType::Minus => OpCode::OpNegate,
             _ => return,
+        };
+
+        let folded = self
+            .literal_at(operand.code_len, self.chunk.code.len())
+            .and_then(|value| fold_unary(op_code, &value));
+        match folded {
+            Some(value) => {
+                self.rewind(operand);
+                self.emit_value(value);
+            }
+            None => self.chunk.write_chunk(op_code, operator),
         }
     }
 
     fn binary(&mut self) {
-        let operator_type = &self.previous.clone().unwrap().t_type;
-        let rule = self.get_rule(&operator_type);
+        let left = self.left_operand;
+        let operator_type = self.previous.t_type;
+        let operator = self.previous.position();
+        let rule = Self::get_rule(operator_type);
+        let right = self.mark();
         self.parse_precedence(rule.precedence.next());
 
-        match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::OpAdd),
-            TokenType::Minus => self.emit_byte(OpCode::OpSubtract),
-            TokenType::Star => self.emit_byte(OpCode::OpMultiply),
-            TokenType::Slash => self.emit_byte(OpCode::OpDivide),
-            TokenType::BangEqual => self.emit_bytes(OpCode::OpEqual, OpCode::OpNot),
-            TokenType::EqualEqual => self.emit_byte(OpCode::OpEqual),
-            TokenType::Greater => self.emit_byte(OpCode::OpGreater),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::OpLess, OpCode::OpNot),
-            TokenType::Less => self.emit_byte(OpCode::OpLess),
-            TokenType::LessEqual => self.emit_bytes(OpCode::OpGreater, OpCode::OpNot),
+        let op_codes: &[OpCode] = match operator_type {
+            TokenType::Plus => &[OpCode::OpAdd],
+            TokenType::Minus => &[OpCode::OpSubtract],
+            TokenType::Star => &[OpCode::OpMultiply],
+            TokenType::Slash => &[OpCode::OpDivide],
+            TokenType::BangEqual => &[OpCode::OpNotEqual],
+            TokenType::EqualEqual => &[OpCode::OpEqual],
+            TokenType::Greater => &[OpCode::OpGreater],
+            TokenType::GreaterEqual => &[OpCode::OpGreaterEqual],
+            TokenType::Less => &[OpCode::OpLess],
+            TokenType::LessEqual => &[OpCode::OpLessEqual],
             _ => return,
+        };
+
+        match self.fold_binary_operands(left, right, op_codes) {
+            Some(value) => {
+                self.rewind(left);
+                self.emit_value(value);
+            }
+            None if op_codes == [OpCode::OpAdd] && self.fuse_constant_add(right, operator) => {}
+            None => {
+                for op_code in op_codes {
+                    self.chunk.write_chunk(*op_code, operator);
+                }
+            }
+        }
+    }
+
+    /// Turn a right operand that is a single short `OpConstant` into an
+    /// `OpConstantAdd`, saving the dispatch of a separate `OpAdd`. The fused
+    /// instruction is the one that can fail, so it takes the `+`'s position.
+    fn fuse_constant_add(&mut self, right: Mark, operator: Position) -> bool {
+        let start = right.code_len;
+        let is_short_constant = self.chunk.code.get(start) == Some(&OpCode::OpConstant.into())
+            && start + 2 == self.chunk.code.len();
+        if is_short_constant {
+            let index = self.chunk.code[start + 1];
+            self.chunk.truncate(start);
+            self.chunk.write_chunk(OpCode::OpConstantAdd, operator);
+            self.chunk.write_chunk(index, operator);
+        }
+        is_short_constant
+    }
+
+    /// Fold `op_codes` over the operands emitted from `left` and `right` to
+    /// the end of the chunk, if both are single literal instructions.
+    fn fold_binary_operands(&self, left: Mark, right: Mark, op_codes: &[OpCode]) -> Option<Value> {
+        let a = self.literal_at(left.code_len, right.code_len)?;
+        let b = self.literal_at(right.code_len, self.chunk.code.len())?;
+
+        let (op_code, rest) = op_codes.split_first()?;
+        rest.iter()
+            .try_fold(fold_binary(*op_code, &a, &b)?, |value, op_code| {
+                fold_unary(*op_code, &value)
+            })
+    }
+
+    /// The value pushed by the code in `start..end`, if that code is exactly
+    /// one instruction loading a literal.
+    fn literal_at(&self, start: usize, end: usize) -> Option<Value> {
+        let op_code = OpCode::from_byte(*self.chunk.code.get(start)?)?;
+        if start + 1 + op_code.operand_len() != end {
+            return None;
+        }
+
+        match op_code {
+            OpCode::OpConstant | OpCode::OpConstantLong => {
+                let index = self.chunk.read_constant_index(start);
+                Some(self.chunk.constants[index].clone())
+            }
+            OpCode::OpNil => Some(Value::nil()),
+            OpCode::OpTrue => Some(Value::bool(true)),
+            OpCode::OpFalse => Some(Value::bool(false)),
+            _ => None,
         }
     }
 
+    fn mark(&self) -> Mark {
+        Mark {
+            code_len: self.chunk.code.len(),
+            constants_len: self.chunk.constants.len(),
+        }
+    }
+
+    fn rewind(&mut self, mark: Mark) {
+        self.chunk.truncate(mark.code_len);
+        self.chunk.constants.truncate(mark.constants_len);
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
-        let rule = self.get_rule(&self.previous.clone().unwrap().t_type);
-        let prefix_rule = &rule.prefix.clone();
-        if let Some(prefix_parse_fn) = prefix_rule {
-            self.dispatch_parse_fn(prefix_parse_fn);
-
-            while precedence
-                <= self
-                    .get_rule(&self.current.clone().unwrap().t_type)
-                    .precedence
-            {
+        if let Some(prefix_parse_fn) = Self::get_rule(self.previous.t_type).prefix {
+            let start = self.mark();
+            prefix_parse_fn(self);
+
+            while precedence <= Self::get_rule(self.current.t_type).precedence {
                 self.advance();
-                let infix_rule = self
-                    .get_rule(&self.previous.clone().unwrap().t_type)
-                    .clone();
-                if let Some(infix_parse_fn) = &infix_rule.infix {
-                    self.dispatch_parse_fn(infix_parse_fn);
+                if let Some(infix_parse_fn) = Self::get_rule(self.previous.t_type).infix {
+                    self.left_operand = start;
+                    infix_parse_fn(self);
                 }
             }
             return;
@@ -530,20 +386,26 @@ impl Parser {
         self.error("Expect expression.");
     }
 
-    fn emit_byte(&mut self, op_code: OpCode) {
-        let token = &self.previous.clone().unwrap();
-        self.chunk.write_chunk(op_code, token.line);
+    fn emit_byte(&mut self, byte: impl Into<u8>) {
+        self.chunk.write_chunk(byte, self.previous.position());
     }
 
-    fn emit_bytes(&mut self, op_code_0: OpCode, op_code_1: OpCode) {
-        let token = &self.previous.clone().unwrap();
-        self.chunk.write_chunk(op_code_0, token.line);
-        self.chunk.write_chunk(op_code_1, token.line);
+    fn emit_constant(&mut self, value: Value) {
+        if self.chunk.constants.len() >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return;
+        }
+
+        self.chunk.write_constant(value, self.previous.position());
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let index = self.chunk.add_constant(value);
-        self.emit_byte(OpCode::OpConstant { index });
+    fn emit_value(&mut self, value: Value) {
+        match value.as_bool() {
+            Some(true) => self.emit_byte(OpCode::OpTrue),
+            Some(false) => self.emit_byte(OpCode::OpFalse),
+            None if value.is_nil() => self.emit_byte(OpCode::OpNil),
+            None => self.emit_constant(value),
+        }
     }
 
     fn emit_return(&mut self) {
@@ -551,21 +413,19 @@ impl Parser {
     }
 
     fn error_at_current(&mut self, message: &str) {
-        let token = &self.current.clone().unwrap();
-        self.error_at(token, message);
+        self.error_at(self.current, message);
     }
 
     fn error(&mut self, message: &str) {
-        let token = &self.previous.clone().unwrap();
-        self.error_at(token, message);
+        self.error_at(self.previous, message);
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
+    fn error_at(&mut self, token: Token, message: &str) {
         if self.had_error {
             return;
         }
 
-        eprint!("[line {}] Error", token.line);
+        eprint!("{}:{}:{}: Error", self.chunk.file, token.line, token.column);
 
         if token.t_type == TokenType::Eof {
             eprint!(" at end")
@@ -579,3 +439,73 @@ impl Parser {
         self.had_error = true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{InterpretResult, Vm};
+
+    fn compile(source: &str) -> Chunk {
+        Parser::new(source).compile().unwrap()
+    }
+
+    /// Source position of the first instruction in `source`'s compiled code
+    /// that is `op_code`.
+    fn position_of(source: &str, op_code: OpCode) -> (usize, usize) {
+        let chunk = compile(source);
+        let mut offset = 0;
+        while chunk.code[offset] != op_code as u8 {
+            offset += 1 + OpCode::from_byte(chunk.code[offset]).unwrap().operand_len();
+        }
+        (chunk.get_line(offset), chunk.get_column(offset))
+    }
+
+    #[test]
+    fn operators_are_positioned_at_the_operator() {
+        assert_eq!(position_of("(1) + (nil)", OpCode::OpAdd), (1, 5));
+        assert_eq!(position_of("-(true)", OpCode::OpNegate), (1, 1));
+        assert_eq!(position_of("!nil <\n  (true)", OpCode::OpLess), (1, 6));
+        assert_eq!(position_of("-true + (1)", OpCode::OpConstantAdd), (1, 7));
+        // Columns count characters, not bytes.
+        assert_eq!(position_of("/* ā */ -true", OpCode::OpNegate), (1, 9));
+        assert_eq!(position_of("/* ā */\n-true", OpCode::OpNegate), (2, 1));
+    }
+
+    #[test]
+    fn literal_expression_folds_to_one_constant() {
+        let chunk = compile("-(1 + 2) * 3");
+        assert_eq!(
+            chunk.code,
+            [OpCode::OpConstant as u8, 0, OpCode::OpReturn as u8]
+        );
+        assert_eq!(chunk.constants, [Value::number(-9.0)]);
+    }
+
+    #[test]
+    fn folding_keeps_operand_order() {
+        let chunk = compile("3 - 1 == 2");
+        assert_eq!(chunk.code, [OpCode::OpTrue as u8, OpCode::OpReturn as u8]);
+        assert_eq!(compile("8 / 2 - 1").constants, [Value::number(3.0)]);
+    }
+
+    #[test]
+    fn folding_rewinds_the_constant_pool() {
+        assert_eq!(compile("1 + 2 + 3 * 4").constants, [Value::number(15.0)]);
+        assert!(compile("1 < 2 == !nil").constants.is_empty());
+    }
+
+    #[test]
+    fn failing_operations_are_left_for_runtime() {
+        for (source, op_code) in [("-true", OpCode::OpNegate), ("1 + nil", OpCode::OpAdd)] {
+            let chunk = compile(source);
+            assert!(chunk.code.contains(&(op_code as u8)), "{}", source);
+
+            let mut vm = Vm::new();
+            assert!(
+                matches!(vm.interpret_src(source), InterpretResult::RuntimeError),
+                "{}",
+                source
+            );
+        }
+    }
+}
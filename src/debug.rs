@@ -1,49 +1,50 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::stats::PairStats;
 use crate::value::Value;
 
 pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
     println!("== {} ==", name);
     let mut offset: usize = 0;
-    let mut prev_line = None;
-    chunk
-        .code
-        .iter()
-        .zip(chunk.lines.iter())
-        .for_each(|(c, l)| {
-            offset = disassemble_instruction(chunk, c, offset, l, &prev_line);
-            prev_line = Some(l);
-        });
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(chunk, offset);
+    }
 }
 
-fn disassemble_instruction(
-    chunk: &Chunk,
-    c: &OpCode,
-    offset: usize,
-    line: &usize,
-    prev_line: &Option<&usize>,
-) -> usize {
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
     print!("{:0>4} ", offset);
-    if prev_line == &Some(line) {
+    let line = chunk.get_line(offset);
+    if offset > 0 && line == chunk.get_line(offset - 1) {
         print!("   | ");
     } else {
         print!("{:>4} ", line);
     }
 
-    match c {
-        OpCode::OpReturn => simple_instruction("OpReturn", offset),
-        OpCode::OpConstant { index } => constant_instruction("OpConstant", chunk, index, offset),
-        OpCode::OpNegate => simple_instruction("OpNegate", offset),
-        OpCode::OpAdd => simple_instruction("OpAdd", offset),
-        OpCode::OpSubtract => simple_instruction("OpSubtract", offset),
-        OpCode::OpMultiply => simple_instruction("OpMultiply", offset),
-        OpCode::OpDivide => simple_instruction("OpDivide", offset),
-        OpCode::OpNil => simple_instruction("OpNil", offset),
-        OpCode::OpTrue => simple_instruction("OpTrue", offset),
-        OpCode::OpFalse => simple_instruction("OpFalse", offset),
-        OpCode::OpNot => simple_instruction("OpNot", offset),
-        OpCode::OpEqual => simple_instruction("OpEqual", offset),
-        OpCode::OpGreater => simple_instruction("OpGreater", offset),
-        OpCode::OpLess => simple_instruction("OpLess", offset),
+    let byte = chunk.code[offset];
+    match OpCode::from_byte(byte) {
+        Some(OpCode::OpReturn) => simple_instruction("OpReturn", offset),
+        Some(OpCode::OpConstant) => constant_instruction("OpConstant", chunk, offset),
+        Some(OpCode::OpConstantLong) => constant_instruction("OpConstantLong", chunk, offset),
+        Some(OpCode::OpConstantAdd) => constant_instruction("OpConstantAdd", chunk, offset),
+        Some(OpCode::OpNegate) => simple_instruction("OpNegate", offset),
+        Some(OpCode::OpAdd) => simple_instruction("OpAdd", offset),
+        Some(OpCode::OpAddNumber) => simple_instruction("OpAddNumber", offset),
+        Some(OpCode::OpSubtract) => simple_instruction("OpSubtract", offset),
+        Some(OpCode::OpMultiply) => simple_instruction("OpMultiply", offset),
+        Some(OpCode::OpDivide) => simple_instruction("OpDivide", offset),
+        Some(OpCode::OpNil) => simple_instruction("OpNil", offset),
+        Some(OpCode::OpTrue) => simple_instruction("OpTrue", offset),
+        Some(OpCode::OpFalse) => simple_instruction("OpFalse", offset),
+        Some(OpCode::OpNot) => simple_instruction("OpNot", offset),
+        Some(OpCode::OpEqual) => simple_instruction("OpEqual", offset),
+        Some(OpCode::OpGreater) => simple_instruction("OpGreater", offset),
+        Some(OpCode::OpLess) => simple_instruction("OpLess", offset),
+        Some(OpCode::OpNotEqual) => simple_instruction("OpNotEqual", offset),
+        Some(OpCode::OpGreaterEqual) => simple_instruction("OpGreaterEqual", offset),
+        Some(OpCode::OpLessEqual) => simple_instruction("OpLessEqual", offset),
+        None => {
+            println!("Unknown opcode {}", byte);
+            offset + 1
+        }
     }
 }
 
@@ -52,23 +53,31 @@ fn simple_instruction(name: &str, offset: usize) -> usize {
     offset + 1
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, index: &usize, offset: usize) -> usize {
+fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let index = chunk.read_constant_index(offset);
     print!("{} {} '", name, index);
-    print_value(&chunk.constants[*index]);
+    print_value(&chunk.constants[index]);
     println!("'");
-    offset + 2
+    offset + 1 + OpCode::from_byte(chunk.code[offset]).unwrap().operand_len()
+}
+
+pub fn print_pair_stats(stats: &PairStats) {
+    let pairs = stats.pairs();
+    let total: u64 = pairs.iter().map(|(_, _, count)| count).sum();
+    eprintln!("== opcode pairs ==");
+    for (first, second, count) in pairs {
+        let name = format!("{:?} -> {:?}", first, second);
+        let percent = count as f64 * 100.0 / total as f64;
+        eprintln!("{:<36} {:>10} {:>6.2}%", name, count, percent);
+    }
 }
 
 pub fn print_value(value: &Value) {
-    match value {
-        Value::Bool(b) => {
-            print!("{}", b);
-        }
-        Value::Nil => {
-            print!("<nil>");
-        }
-        Value::Number(n) => {
-            print!("{}", n);
-        }
+    if let Some(b) = value.as_bool() {
+        print!("{}", b);
+    } else if let Some(n) = value.as_number() {
+        print!("{}", n);
+    } else {
+        print!("<nil>");
     }
 }
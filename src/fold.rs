@@ -0,0 +1,40 @@
+use crate::chunk::OpCode;
+use crate::value::Value;
+use std::cmp::Ordering;
+
+/// Evaluate a unary instruction on a constant operand at compile time.
+///
+/// Returns `None` when the VM would raise a runtime error, so the compiler
+/// leaves those instructions in place and the error still happens at runtime.
+pub(crate) fn fold_unary(op_code: OpCode, operand: &Value) -> Option<Value> {
+    match op_code {
+        OpCode::OpNegate => operand.as_number().map(|n| Value::number(-n)),
+        OpCode::OpNot => Some(Value::bool(operand.is_falsy())),
+        _ => None,
+    }
+}
+
+/// Evaluate a binary instruction on two constant operands at compile time,
+/// with the same semantics and the same `None`-on-error rule as [`fold_unary`].
+pub(crate) fn fold_binary(op_code: OpCode, a: &Value, b: &Value) -> Option<Value> {
+    match op_code {
+        OpCode::OpEqual => return Some(Value::bool(a.equals(b))),
+        OpCode::OpNotEqual => return Some(Value::bool(!a.equals(b))),
+        _ => {}
+    }
+
+    let (Some(a), Some(b)) = (a.as_number(), b.as_number()) else {
+        return None;
+    };
+    match op_code {
+        OpCode::OpAdd => Some(Value::number(a + b)),
+        OpCode::OpSubtract => Some(Value::number(a - b)),
+        OpCode::OpMultiply => Some(Value::number(a * b)),
+        OpCode::OpDivide => Some(Value::number(a / b)),
+        OpCode::OpGreater => Some(Value::bool(a > b)),
+        OpCode::OpLess => Some(Value::bool(a < b)),
+        OpCode::OpGreaterEqual => Some(Value::bool(a.partial_cmp(&b) != Some(Ordering::Less))),
+        OpCode::OpLessEqual => Some(Value::bool(a.partial_cmp(&b) != Some(Ordering::Greater))),
+        _ => None,
+    }
+}
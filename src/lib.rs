@@ -0,0 +1,14 @@
+pub mod chunk;
+pub mod compile;
+pub mod debug;
+mod fold;
+pub mod peephole;
+pub mod register;
+pub mod scanner;
+mod serialize;
+pub mod stats;
+pub mod value;
+pub mod verify;
+pub mod vm;
+
+pub use serialize::DecodeError;
@@ -1,63 +1,119 @@
-use crate::chunk::OpCode::{OpAdd, OpConstant, OpDivide, OpNegate, OpReturn};
-use crate::chunk::{Chunk, OpCode};
-use crate::debug::disassemble_chunk;
-use crate::vm::Vm;
+use clox::chunk::Chunk;
+use clox::compile::Parser;
+use clox::debug::print_pair_stats;
+use clox::vm::{Backend, InterpretResult, Vm};
 use std::env::args;
-use std::fs::File;
+use std::fs;
 use std::io;
 use std::io::BufRead;
 use std::process::exit;
 
-mod chunk;
-mod compile;
-mod debug;
-mod scanner;
-mod value;
-mod vm;
-
 fn main() {
-    // let mut chunk = Chunk::new();
-    //
-    // let constant = chunk.add_constant(1.2);
-    // chunk.write_chunk(OpConstant { index: constant }, 123);
-    //
-    // let constant = chunk.add_constant(3.4);
-    // chunk.write_chunk(OpCode::OpConstant { index: constant}, 123);
-    // chunk.write_chunk(OpAdd, 123);
-    //
-    // let constant = chunk.add_constant(5.6);
-    // chunk.write_chunk(OpCode::OpConstant { index: constant}, 123);
-    //
-    // chunk.write_chunk(OpDivide, 123);
-    // chunk.write_chunk(OpNegate, 123);
-    // chunk.write_chunk(OpReturn, 123);
-
     let mut vm = Vm::new();
-    let mut args = args();
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, args.nth(1).unwrap().as_mut_str()),
+    let mut args: Vec<String> = args().collect();
+    let stats = args.iter().any(|arg| arg == "--stats");
+    if stats {
+        args.retain(|arg| arg != "--stats");
+        vm.enable_stats();
+    }
+    if let Some(index) = args.iter().position(|arg| arg.starts_with("--backend=")) {
+        let arg = args.remove(index);
+        match &arg["--backend=".len()..] {
+            "stack" => vm.set_backend(Backend::Stack),
+            "register" => vm.set_backend(Backend::Register),
+            name => {
+                eprintln!("Unknown backend \"{}\".", name);
+                exit(64);
+            }
+        }
+    }
+
+    let result = match args.as_slice() {
+        [_] => {
+            repl(&mut vm);
+            InterpretResult::Ok
+        }
+        [_, command, path, flag, output] if command == "compile" && flag == "-o" => {
+            compile_file(path, output);
+            InterpretResult::Ok
+        }
+        [_, command, path] if command == "run" => run_file(&mut vm, path),
+        [_, path] => run_file(&mut vm, path),
         _ => {
-            println!("Usage: clox [path]");
+            println!("Usage: clox [--stats] [--backend=stack|register] [path]");
+            println!("       clox compile <path> -o <output.loxc>");
+            println!("       clox run [--stats] [--backend=stack|register] <path>");
             exit(64);
         }
-    }
+    };
 
-    vm.run();
+    if let Some(stats) = vm.stats() {
+        print_pair_stats(stats);
+    }
 
-    // disassemble_chunk(&chunk, "test chunk");
+    match result {
+        InterpretResult::Ok => {}
+        InterpretResult::CompileError => exit(65),
+        InterpretResult::RuntimeError => exit(70),
+    }
 }
 
 fn repl(vm: &mut Vm) {
     let stdin = io::stdin();
     print!("> ");
     for line in stdin.lock().lines() {
-        vm.interpret_src(&line.unwrap());
+        vm.interpret_file("<stdin>", &line.unwrap());
         print!("> ");
     }
 }
 
-fn run_file(vm: &mut Vm, path: &str) {
-    let source = std::io::read_to_string(File::open(path).unwrap()).unwrap();
-    let result = vm.interpret_src(&source);
+fn run_file(vm: &mut Vm, path: &str) -> InterpretResult {
+    if path.ends_with(".loxc") {
+        let chunk = read_chunk(path);
+        vm.interpret_chunk(chunk)
+    } else {
+        let source = read_source(path);
+        vm.interpret_file(path, &source)
+    }
+}
+
+fn compile_file(path: &str, output: &str) {
+    let source = read_source(path);
+    let chunk = match Parser::new(&source).with_file(path).compile() {
+        Some(chunk) => chunk,
+        None => exit(65),
+    };
+
+    if let Err(err) = fs::write(output, chunk.to_bytes()) {
+        eprintln!("Could not write \"{}\": {}.", output, err);
+        exit(74);
+    }
+}
+
+fn read_source(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Could not read \"{}\": {}.", path, err);
+            exit(74);
+        }
+    }
+}
+
+fn read_chunk(path: &str) -> Chunk {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Could not read \"{}\": {}.", path, err);
+            exit(74);
+        }
+    };
+
+    match Chunk::from_bytes(&bytes) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            eprintln!("Could not load \"{}\": {}.", path, err);
+            exit(65);
+        }
+    }
 }
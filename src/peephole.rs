@@ -0,0 +1,237 @@
+use crate::chunk::{Chunk, OpCode, Position};
+
+/// A decoded instruction together with the source position it was emitted for.
+#[derive(Clone, Debug)]
+struct Instruction {
+    op_code: OpCode,
+    /// Constant index operand of `OpConstant`, `OpConstantLong` and `OpConstantAdd`.
+    constant: usize,
+    position: Position,
+}
+
+/// Rewrite short instruction sequences in a finished chunk into cheaper ones.
+///
+/// Instructions are decoded and pushed onto an output list one at a time,
+/// rewriting the tail of that list until no pattern matches, so rewrites that
+/// enable further rewrites cascade in a single pass. The result is re-encoded
+/// and the line table rebuilt from each surviving instruction's position. A
+/// fused instruction keeps the position of the first instruction it replaces,
+/// which is the one that can raise a runtime error.
+///
+/// Code is straight-line today. Once jumps exist, sequences that span a jump
+/// target must be left alone and jump offsets re-patched after re-encoding.
+pub fn optimize(chunk: &Chunk) -> Chunk {
+    let mut optimized = Chunk::with_constants(chunk.constants.clone());
+    optimized.file = chunk.file.clone();
+
+    let mut instructions = vec![];
+    for instruction in decode(chunk) {
+        instructions.push(instruction);
+        while rewrite_tail(&mut instructions) {}
+    }
+
+    for instruction in instructions {
+        let position = instruction.position;
+        match instruction.op_code {
+            OpCode::OpConstant | OpCode::OpConstantLong => {
+                optimized.write_constant_index(instruction.constant, position)
+            }
+            OpCode::OpConstantAdd => {
+                optimized.write_chunk(OpCode::OpConstantAdd, position);
+                optimized.write_chunk(instruction.constant as u8, position);
+            }
+            op_code => optimized.write_chunk(op_code, position),
+        }
+    }
+    optimized
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op_code = OpCode::from_byte(chunk.code[offset]).unwrap();
+        let constant = match op_code {
+            OpCode::OpConstant | OpCode::OpConstantLong | OpCode::OpConstantAdd => {
+                chunk.read_constant_index(offset)
+            }
+            _ => 0,
+        };
+        instructions.push(Instruction {
+            op_code,
+            constant,
+            position: chunk.get_position(offset),
+        });
+        offset += 1 + op_code.operand_len();
+    }
+    instructions
+}
+
+/// Apply one rewrite to the last few instructions, returning whether any matched.
+fn rewrite_tail(instructions: &mut Vec<Instruction>) -> bool {
+    let len = instructions.len();
+    let op_at = |back: usize| {
+        len.checked_sub(back)
+            .map(|index| instructions[index].op_code)
+    };
+
+    // `!!x` is `x` when `x` is already a boolean.
+    if op_at(3).is_some_and(produces_bool)
+        && op_at(2) == Some(OpCode::OpNot)
+        && op_at(1) == Some(OpCode::OpNot)
+    {
+        instructions.truncate(len - 2);
+        return true;
+    }
+
+    // An instruction followed by `OpNot` that has a negated form.
+    if op_at(1) == Some(OpCode::OpNot) {
+        if let Some(negated) = op_at(2).and_then(negated) {
+            instructions.pop();
+            instructions[len - 2].op_code = negated;
+            return true;
+        }
+    }
+
+    false
+}
+
+fn produces_bool(op_code: OpCode) -> bool {
+    matches!(
+        op_code,
+        OpCode::OpTrue
+            | OpCode::OpFalse
+            | OpCode::OpNot
+            | OpCode::OpEqual
+            | OpCode::OpGreater
+            | OpCode::OpLess
+            | OpCode::OpNotEqual
+            | OpCode::OpGreaterEqual
+            | OpCode::OpLessEqual
+    )
+}
+
+/// The single instruction equivalent to `op_code` followed by `OpNot`.
+fn negated(op_code: OpCode) -> Option<OpCode> {
+    match op_code {
+        OpCode::OpNil | OpCode::OpFalse => Some(OpCode::OpTrue),
+        OpCode::OpTrue => Some(OpCode::OpFalse),
+        OpCode::OpEqual => Some(OpCode::OpNotEqual),
+        OpCode::OpNotEqual => Some(OpCode::OpEqual),
+        OpCode::OpLess => Some(OpCode::OpGreaterEqual),
+        OpCode::OpGreaterEqual => Some(OpCode::OpLess),
+        OpCode::OpGreater => Some(OpCode::OpLessEqual),
+        OpCode::OpLessEqual => Some(OpCode::OpGreater),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chunk running `op_codes` in order, each on its own line.
+    fn chunk(op_codes: &[OpCode]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for (line, op_code) in op_codes.iter().enumerate() {
+            let position = Position {
+                line: line + 1,
+                ..Position::default()
+            };
+            chunk.write_chunk(*op_code, position);
+        }
+        chunk
+    }
+
+    fn code(op_codes: &[OpCode]) -> Vec<u8> {
+        op_codes.iter().map(|op_code| *op_code as u8).collect()
+    }
+
+    #[test]
+    fn not_after_comparison_becomes_the_opposite_comparison() {
+        let pairs = [
+            (OpCode::OpEqual, OpCode::OpNotEqual),
+            (OpCode::OpNotEqual, OpCode::OpEqual),
+            (OpCode::OpLess, OpCode::OpGreaterEqual),
+            (OpCode::OpGreaterEqual, OpCode::OpLess),
+            (OpCode::OpGreater, OpCode::OpLessEqual),
+            (OpCode::OpLessEqual, OpCode::OpGreater),
+        ];
+        for (comparison, opposite) in pairs {
+            let optimized = optimize(&chunk(&[
+                OpCode::OpNil,
+                OpCode::OpNil,
+                comparison,
+                OpCode::OpNot,
+                OpCode::OpReturn,
+            ]));
+            assert_eq!(
+                optimized.code,
+                code(&[OpCode::OpNil, OpCode::OpNil, opposite, OpCode::OpReturn]),
+                "{:?}",
+                comparison
+            );
+        }
+    }
+
+    #[test]
+    fn double_not_after_boolean_is_dropped() {
+        let optimized = optimize(&chunk(&[
+            OpCode::OpNil,
+            OpCode::OpNegate,
+            OpCode::OpNot,
+            OpCode::OpNot,
+            OpCode::OpNot,
+            OpCode::OpReturn,
+        ]));
+        assert_eq!(
+            optimized.code,
+            code(&[
+                OpCode::OpNil,
+                OpCode::OpNegate,
+                OpCode::OpNot,
+                OpCode::OpReturn
+            ])
+        );
+
+        // `!!` turns any other value into a boolean, so it has to stay.
+        let unchanged = chunk(&[
+            OpCode::OpNil,
+            OpCode::OpNegate,
+            OpCode::OpNot,
+            OpCode::OpNot,
+            OpCode::OpReturn,
+        ]);
+        assert_eq!(optimize(&unchanged).code, unchanged.code);
+    }
+
+    #[test]
+    fn rewritten_code_keeps_its_line_table_in_step() {
+        let optimized = optimize(&chunk(&[
+            OpCode::OpNil,
+            OpCode::OpTrue,
+            OpCode::OpLess,
+            OpCode::OpNot,
+            OpCode::OpFalse,
+            OpCode::OpEqual,
+            OpCode::OpReturn,
+        ]));
+        assert_eq!(
+            optimized.code,
+            code(&[
+                OpCode::OpNil,
+                OpCode::OpTrue,
+                OpCode::OpGreaterEqual,
+                OpCode::OpFalse,
+                OpCode::OpEqual,
+                OpCode::OpReturn,
+            ])
+        );
+        // The fused comparison keeps the `OpLess` line; later code keeps its own.
+        let lines: Vec<usize> = (0..optimized.code.len())
+            .map(|offset| optimized.get_line(offset))
+            .collect();
+        assert_eq!(lines, [1, 2, 3, 5, 6, 7]);
+        assert_eq!(crate::verify::verify(&optimized), Ok(()));
+    }
+}
@@ -0,0 +1,424 @@
+//! Three-address code for the register backend.
+//!
+//! Register code is lowered from verified stack bytecode rather than compiled
+//! from source, so constant folding and the peephole pass apply to both
+//! backends. Each stack slot gets its own register, numbered by its depth, so
+//! the lowering is a single pass with no allocator to speak of. What it saves
+//! is dispatches: a constant pushed onto the stack is never loaded into a
+//! register, but becomes an operand of the instruction that consumes it.
+//!
+//! Instructions are an opcode byte followed by 16-bit little-endian operands.
+//! A source operand with [`CONSTANT`] set names a constant pool entry instead
+//! of a register.
+
+use crate::chunk::{Chunk, OpCode};
+use crate::verify::VerifyError;
+
+/// Set in a source operand that refers to a constant rather than a register.
+pub const CONSTANT: u16 = 0x8000;
+
+/// Registers are addressed by the 15 bits an operand has without [`CONSTANT`].
+pub const MAX_REGISTERS: usize = CONSTANT as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RegOpCode {
+    /// `dst = constants[k]`; register operand, then a three-byte constant
+    /// index. Only for constants too far into the pool to be an operand.
+    LoadConstant,
+    LoadNil,
+    LoadTrue,
+    LoadFalse,
+    /// `dst = -a`.
+    Negate,
+    /// `dst = !a`.
+    Not,
+    /// `dst = a + b`, and likewise for the other binary instructions.
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    /// Finish the script with the value of `a`.
+    Return,
+}
+
+impl RegOpCode {
+    const ALL: [RegOpCode; 17] = [
+        RegOpCode::LoadConstant,
+        RegOpCode::LoadNil,
+        RegOpCode::LoadTrue,
+        RegOpCode::LoadFalse,
+        RegOpCode::Negate,
+        RegOpCode::Not,
+        RegOpCode::Add,
+        RegOpCode::Subtract,
+        RegOpCode::Multiply,
+        RegOpCode::Divide,
+        RegOpCode::Equal,
+        RegOpCode::NotEqual,
+        RegOpCode::Greater,
+        RegOpCode::Less,
+        RegOpCode::GreaterEqual,
+        RegOpCode::LessEqual,
+        RegOpCode::Return,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    /// Number of operand bytes that follow the opcode in the code.
+    pub fn operand_len(self) -> usize {
+        match self {
+            RegOpCode::LoadConstant => 5,
+            RegOpCode::LoadNil | RegOpCode::LoadTrue | RegOpCode::LoadFalse | RegOpCode::Return => {
+                2
+            }
+            RegOpCode::Negate | RegOpCode::Not => 4,
+            _ => 6,
+        }
+    }
+
+    /// The stack instruction computing the same numeric binary operation.
+    pub(crate) fn numeric_op_code(self) -> Option<OpCode> {
+        match self {
+            RegOpCode::Add => Some(OpCode::OpAdd),
+            RegOpCode::Subtract => Some(OpCode::OpSubtract),
+            RegOpCode::Multiply => Some(OpCode::OpMultiply),
+            RegOpCode::Divide => Some(OpCode::OpDivide),
+            RegOpCode::Greater => Some(OpCode::OpGreater),
+            RegOpCode::Less => Some(OpCode::OpLess),
+            RegOpCode::GreaterEqual => Some(OpCode::OpGreaterEqual),
+            RegOpCode::LessEqual => Some(OpCode::OpLessEqual),
+            _ => None,
+        }
+    }
+}
+
+impl From<RegOpCode> for u8 {
+    fn from(op_code: RegOpCode) -> Self {
+        op_code as u8
+    }
+}
+
+/// Register code together with the size of the register file it needs.
+#[derive(Clone, Debug)]
+pub struct RegisterChunk {
+    /// Code, line table and constants; the code holds [`RegOpCode`]s.
+    pub chunk: Chunk,
+    pub registers: usize,
+}
+
+/// Lower stack bytecode that has passed [`verify`](crate::verify::verify)
+/// into register code.
+///
+/// Code that runs off its end without an `OpReturn` finishes with whatever
+/// is on top of the stack, so the lowering ends such code with a `Return` of
+/// the top slot.
+///
+/// Fails only if the stack would grow deeper than [`MAX_REGISTERS`].
+pub fn lower(chunk: &Chunk) -> Result<RegisterChunk, VerifyError> {
+    let mut lowered = RegisterChunk {
+        chunk: Chunk::with_constants(chunk.constants.clone()),
+        registers: 0,
+    };
+    lowered.chunk.file = chunk.file.clone();
+    // The operand holding each value on the simulated stack.
+    let mut slots: Vec<u16> = vec![];
+    let mut returned = false;
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op_code = OpCode::from_byte(chunk.code[offset]).unwrap();
+        let position = chunk.get_position(offset);
+        let mut emit = |op: RegOpCode, operands: &[u16]| {
+            lowered.chunk.write_chunk(op, position);
+            for operand in operands {
+                for byte in operand.to_le_bytes() {
+                    lowered.chunk.write_chunk(byte, position);
+                }
+            }
+        };
+
+        let depth = slots.len();
+        let register = |index: usize, registers: &mut usize| {
+            if index >= MAX_REGISTERS {
+                return Err(VerifyError::TooManyRegisters { offset });
+            }
+            *registers = (*registers).max(index + 1);
+            Ok(index as u16)
+        };
+
+        match op_code {
+            OpCode::OpConstant | OpCode::OpConstantLong => {
+                let index = chunk.read_constant_index(offset);
+                if index < MAX_REGISTERS {
+                    slots.push(CONSTANT | index as u16);
+                } else {
+                    let dst = register(depth, &mut lowered.registers)?;
+                    emit(RegOpCode::LoadConstant, &[dst]);
+                    for byte in &(index as u32).to_le_bytes()[..3] {
+                        lowered.chunk.write_chunk(*byte, position);
+                    }
+                    slots.push(dst);
+                }
+            }
+            OpCode::OpNil | OpCode::OpTrue | OpCode::OpFalse => {
+                let dst = register(depth, &mut lowered.registers)?;
+                let op = match op_code {
+                    OpCode::OpNil => RegOpCode::LoadNil,
+                    OpCode::OpTrue => RegOpCode::LoadTrue,
+                    _ => RegOpCode::LoadFalse,
+                };
+                emit(op, &[dst]);
+                slots.push(dst);
+            }
+            OpCode::OpNegate | OpCode::OpNot => {
+                let a = slots.pop().unwrap();
+                let dst = register(depth - 1, &mut lowered.registers)?;
+                let op = match op_code {
+                    OpCode::OpNegate => RegOpCode::Negate,
+                    _ => RegOpCode::Not,
+                };
+                emit(op, &[dst, a]);
+                slots.push(dst);
+            }
+            OpCode::OpConstantAdd => {
+                let b = CONSTANT | chunk.read_constant_index(offset) as u16;
+                let a = slots.pop().unwrap();
+                let dst = register(depth - 1, &mut lowered.registers)?;
+                emit(RegOpCode::Add, &[dst, a, b]);
+                slots.push(dst);
+            }
+            OpCode::OpAdd
+            | OpCode::OpAddNumber
+            | OpCode::OpSubtract
+            | OpCode::OpMultiply
+            | OpCode::OpDivide
+            | OpCode::OpEqual
+            | OpCode::OpGreater
+            | OpCode::OpLess
+            | OpCode::OpNotEqual
+            | OpCode::OpGreaterEqual
+            | OpCode::OpLessEqual => {
+                let b = slots.pop().unwrap();
+                let a = slots.pop().unwrap();
+                let dst = register(depth - 2, &mut lowered.registers)?;
+                let op = match op_code {
+                    OpCode::OpAdd | OpCode::OpAddNumber => RegOpCode::Add,
+                    OpCode::OpSubtract => RegOpCode::Subtract,
+                    OpCode::OpMultiply => RegOpCode::Multiply,
+                    OpCode::OpDivide => RegOpCode::Divide,
+                    OpCode::OpEqual => RegOpCode::Equal,
+                    OpCode::OpGreater => RegOpCode::Greater,
+                    OpCode::OpLess => RegOpCode::Less,
+                    OpCode::OpNotEqual => RegOpCode::NotEqual,
+                    OpCode::OpGreaterEqual => RegOpCode::GreaterEqual,
+                    _ => RegOpCode::LessEqual,
+                };
+                emit(op, &[dst, a, b]);
+                slots.push(dst);
+            }
+            OpCode::OpReturn => {
+                let a = slots.pop().unwrap();
+                emit(RegOpCode::Return, &[a]);
+            }
+        }
+
+        returned = op_code == OpCode::OpReturn;
+        offset += 1 + op_code.operand_len();
+    }
+
+    if let (false, Some(&a)) = (returned, slots.last()) {
+        let position = chunk.get_position(chunk.code.len() - 1);
+        lowered.chunk.write_chunk(RegOpCode::Return, position);
+        for byte in a.to_le_bytes() {
+            lowered.chunk.write_chunk(byte, position);
+        }
+    }
+
+    Ok(lowered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Position;
+    use crate::value::Value;
+    use crate::vm::{Backend, InterpretResult, RunStatus, Vm};
+
+    fn run_with(backend: Backend, load: impl FnOnce(&mut Vm)) -> RunStatus {
+        let mut vm = Vm::new();
+        vm.set_backend(backend);
+        load(&mut vm);
+        vm.run_for(usize::MAX)
+    }
+
+    /// Both backends must finish with equal values or both fail.
+    fn assert_same(what: &str, stack: &RunStatus, register: &RunStatus) {
+        match (stack, register) {
+            (RunStatus::Finished(a), RunStatus::Finished(b)) if a == b => {}
+            (RunStatus::RuntimeError, RunStatus::RuntimeError) => {}
+            _ => panic!("{}: stack {:?}, register {:?}", what, stack, register),
+        }
+    }
+
+    /// Run `chunk` on both backends and return the status they agree on.
+    fn run_both(what: &str, chunk: Chunk) -> RunStatus {
+        let stack = run_with(Backend::Stack, |vm| vm.load_chunk(chunk.clone()).unwrap());
+        let register = run_with(Backend::Register, |vm| vm.load_chunk(chunk).unwrap());
+        assert_same(what, &stack, &register);
+        stack
+    }
+
+    fn assert_finished(what: &str, status: RunStatus, expected: Value) {
+        assert!(
+            matches!(&status, RunStatus::Finished(value) if *value == expected),
+            "{}: {:?}",
+            what,
+            status
+        );
+    }
+
+    fn chunk(build: impl FnOnce(&mut Chunk)) -> Chunk {
+        let mut chunk = Chunk::new();
+        build(&mut chunk);
+        chunk
+    }
+
+    #[test]
+    fn opcodes_decode_to_their_discriminant() {
+        for (index, op_code) in RegOpCode::ALL.iter().enumerate() {
+            assert_eq!(*op_code as usize, index);
+            assert_eq!(RegOpCode::from_byte(index as u8), Some(*op_code));
+        }
+        assert_eq!(RegOpCode::from_byte(RegOpCode::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn backends_agree_on_expressions() {
+        let sources = [
+            "1 + 2 * 3 - 4 / 5",
+            "-(1 + 2) * 3",
+            "3 - 1 == 2",
+            "!(1 < 2) == (3 >= 4)",
+            "nil == false",
+            "!nil != !!true",
+            "1 <= 1 == (2 > 1)",
+            "(1 + 2) * -true",
+            "-true + 1",
+            "1 + nil",
+            "nil < 1",
+            "!(1 + 2) + 3",
+        ];
+        for source in sources {
+            let load = |vm: &mut Vm| {
+                assert!(matches!(vm.load_src(source), InterpretResult::Ok));
+            };
+            let stack = run_with(Backend::Stack, load);
+            let register = run_with(Backend::Register, load);
+            assert_same(source, &stack, &register);
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_fused_adds() {
+        let at = Position::default();
+        let add = |op_code: OpCode, left: Value| {
+            chunk(|chunk| {
+                chunk.write_constant(left, at);
+                if op_code == OpCode::OpConstantAdd {
+                    let index = chunk.add_constant(Value::number(2.0));
+                    chunk.write_chunk(op_code, at);
+                    chunk.write_chunk(index as u8, at);
+                } else {
+                    chunk.write_constant(Value::number(2.0), at);
+                    chunk.write_chunk(op_code, at);
+                }
+                chunk.write_chunk(OpCode::OpReturn, at);
+            })
+        };
+
+        for op_code in [OpCode::OpConstantAdd, OpCode::OpAddNumber] {
+            let what = format!("{:?}", op_code);
+            let status = run_both(&what, add(op_code, Value::number(1.0)));
+            assert_finished(&what, status, Value::number(3.0));
+            let status = run_both(&what, add(op_code, Value::nil()));
+            assert!(matches!(status, RunStatus::RuntimeError), "{}", what);
+        }
+    }
+
+    #[test]
+    fn constants_past_operand_range_are_loaded() {
+        let at = Position::default();
+        let chunk = chunk(|chunk| {
+            for n in 0..=MAX_REGISTERS {
+                chunk.add_constant(Value::number(n as f64));
+            }
+            chunk.write_constant_index(MAX_REGISTERS, at);
+            chunk.write_constant_index(1, at);
+            chunk.write_chunk(OpCode::OpAdd, at);
+            chunk.write_chunk(OpCode::OpReturn, at);
+        });
+
+        let lowered = lower(&chunk).unwrap();
+        assert_eq!(lowered.chunk.code[0], RegOpCode::LoadConstant as u8);
+        let expected = Value::number(MAX_REGISTERS as f64 + 1.0);
+        assert_finished("LoadConstant", run_both("LoadConstant", chunk), expected);
+    }
+
+    #[test]
+    fn stack_deeper_than_register_file_is_rejected() {
+        let nils = |count: usize| {
+            chunk(|chunk| {
+                for _ in 0..count {
+                    chunk.write_chunk(OpCode::OpNil, Position::default());
+                }
+            })
+        };
+
+        assert_eq!(
+            lower(&nils(MAX_REGISTERS)).unwrap().registers,
+            MAX_REGISTERS
+        );
+        assert_eq!(
+            lower(&nils(MAX_REGISTERS + 1)).unwrap_err(),
+            VerifyError::TooManyRegisters {
+                offset: MAX_REGISTERS
+            }
+        );
+    }
+
+    #[test]
+    fn running_off_the_end_finishes_with_the_stack_top() {
+        let at = Position::default();
+        let cases = [
+            (chunk(|_| {}), Value::nil()),
+            (
+                chunk(|chunk| {
+                    chunk.write_chunk(OpCode::OpNil, at);
+                    chunk.write_chunk(OpCode::OpTrue, at);
+                }),
+                Value::bool(true),
+            ),
+            (
+                chunk(|chunk| {
+                    chunk.write_chunk(OpCode::OpFalse, at);
+                    chunk.write_chunk(OpCode::OpTrue, at);
+                    chunk.write_chunk(OpCode::OpReturn, at);
+                }),
+                Value::bool(true),
+            ),
+        ];
+        for (chunk, expected) in cases {
+            let what = "fall off the end";
+            assert_finished(what, run_both(what, chunk), expected);
+        }
+    }
+}
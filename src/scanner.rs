@@ -1,19 +1,55 @@
+use crate::chunk::Position;
+use unicode_xid::UnicodeXID;
+
+/// Scans source text into tokens on demand.
+///
+/// `start` and `current` are byte offsets into `source`. ASCII is scanned a
+/// byte at a time; other characters are decoded only where they can start or
+/// continue an identifier, and are otherwise consumed whole, so every token
+/// starts and ends on a character boundary. Columns count characters.
 #[derive(Debug)]
-pub struct Scanner {
-    source: String,
+pub struct Scanner<'src> {
+    source: &'src str,
     start: usize,
     current: usize,
     line: usize,
+    line_start: usize,
+    /// UTF-8 continuation bytes consumed since `line_start`, which do not
+    /// count towards the column.
+    line_continuation_bytes: usize,
+    start_line: usize,
+    start_column: usize,
 }
 
-#[derive(Debug, Clone)]
-pub struct Token {
+/// Byte offsets `start..end` of a token in the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A token borrowing its lexeme from the source, or for `TokenType::Error`
+/// holding the error message instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Token<'src> {
     pub t_type: TokenType,
-    pub token: String,
+    pub token: &'src str,
     pub line: usize,
+    pub column: usize,
+    pub span: Span,
 }
 
-#[derive(Debug, PartialEq, Clone, Eq, Hash)]
+impl Token<'_> {
+    pub fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
+            source_offset: self.span.start,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
 pub enum TokenType {
     LeftParen,
     RightParen,
@@ -61,19 +97,27 @@ pub enum TokenType {
     Eof,
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Self {
+impl<'src> Scanner<'src> {
+    pub fn new(source: &'src str) -> Self {
         Self {
-            source: source.to_string(),
+            source,
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            line_continuation_bytes: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
-    pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+    pub fn scan_token(&mut self) -> Token<'src> {
+        if let Err(error) = self.skip_whitespace() {
+            return error;
+        }
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column();
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -82,93 +126,114 @@ impl Scanner {
         let c = self.advance();
 
         match c {
-            '(' => self.make_token(TokenType::LeftParen),
-            ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
-            ';' => self.make_token(TokenType::Semicolon),
-            ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
-            '!' => {
-                let is_equal_next = self.match_char('=');
+            b'(' => self.make_token(TokenType::LeftParen),
+            b')' => self.make_token(TokenType::RightParen),
+            b'{' => self.make_token(TokenType::LeftBrace),
+            b'}' => self.make_token(TokenType::RightBrace),
+            b';' => self.make_token(TokenType::Semicolon),
+            b',' => self.make_token(TokenType::Comma),
+            b'.' => self.make_token(TokenType::Dot),
+            b'-' => self.make_token(TokenType::Minus),
+            b'+' => self.make_token(TokenType::Plus),
+            b'/' => self.make_token(TokenType::Slash),
+            b'*' => self.make_token(TokenType::Star),
+            b'!' => {
+                let is_equal_next = self.match_char(b'=');
                 self.make_token(if is_equal_next {
                     TokenType::BangEqual
                 } else {
                     TokenType::Bang
                 })
             }
-            '=' => {
-                let is_equal_next = self.match_char('=');
+            b'=' => {
+                let is_equal_next = self.match_char(b'=');
                 self.make_token(if is_equal_next {
                     TokenType::EqualEqual
                 } else {
                     TokenType::Equal
                 })
             }
-            '<' => {
-                let is_equal_next = self.match_char('=');
+            b'<' => {
+                let is_equal_next = self.match_char(b'=');
                 self.make_token(if is_equal_next {
                     TokenType::LessEqual
                 } else {
                     TokenType::Less
                 })
             }
-            '>' => {
-                let is_equal_next = self.match_char('=');
+            b'>' => {
+                let is_equal_next = self.match_char(b'=');
                 self.make_token(if is_equal_next {
                     TokenType::GreaterEqual
                 } else {
                     TokenType::Greater
                 })
             }
-            '"' => self.string(),
-            '0'..='9' => self.number(),
-            'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
-            _ => self.error_token("Unexpected character."),
+            b'"' => self.string(),
+            b'0'..=b'9' => self.number(),
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => self.identifier(),
+            _ => {
+                self.current = self.start;
+                let c = self.advance_char();
+                if c.is_xid_start() {
+                    self.identifier()
+                } else {
+                    self.error_token("Unexpected character.")
+                }
+            }
         }
     }
 
-    fn advance(&mut self) -> char {
+    fn advance(&mut self) -> u8 {
         self.current += 1;
-        self.char_at(self.current - 1)
+        let byte = self.byte_at(self.current - 1);
+        if is_continuation(byte) {
+            self.line_continuation_bytes += 1;
+        }
+        byte
     }
 
-    fn char_at(&self, index: usize) -> char {
-        if index >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(index).unwrap()
+    /// Consume the whole character at `current`.
+    fn advance_char(&mut self) -> char {
+        let c = self.peek_char();
+        for _ in 0..c.len_utf8() {
+            self.advance();
         }
+        c
     }
 
-    fn peek(&self) -> char {
-        self.char_at(self.current)
+    /// The character at `current`, or `'\0'` at the end of the source.
+    fn peek_char(&self) -> char {
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
-    fn slice(&self) -> String {
-        let letters: Vec<String> = (self.start..self.current)
-            .map(|i| self.char_at(i).to_string())
-            .collect();
+    /// The byte at `index`, or `0` past the end of the source.
+    fn byte_at(&self, index: usize) -> u8 {
+        self.source.as_bytes().get(index).copied().unwrap_or(b'\0')
+    }
 
-        letters.join("").to_string()
+    fn peek(&self) -> u8 {
+        self.byte_at(self.current)
     }
 
-    fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.char_at(self.current + 1)
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
         }
     }
 
-    fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            false
-        } else if self.peek() != expected {
+    /// The lexeme scanned so far.
+    fn slice(&self) -> &'src str {
+        &self.source[self.start..self.current]
+    }
+
+    fn peek_next(&self) -> u8 {
+        self.byte_at(self.current + 1)
+    }
+
+    fn match_char(&mut self, expected: u8) -> bool {
+        if self.is_at_end() || self.peek() != expected {
             false
         } else {
             self.current += 1;
@@ -180,140 +245,555 @@ impl Scanner {
         self.current >= self.source.len()
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Skip whitespace and comments, failing with an error token if a block
+    /// comment is never closed.
+    fn skip_whitespace(&mut self) -> Result<(), Token<'src>> {
         loop {
-            let ch = self.peek();
-            match ch {
-                ' ' | '\r' | '\t' => {
+            match self.peek() {
+                b' ' | b'\r' | b'\t' => {
                     self.advance();
                 }
-                '\n' => {
-                    self.line += 1;
+                b'\n' => {
                     self.advance();
+                    self.new_line();
+                }
+                b'/' if self.peek_next() == b'/' => {
+                    while self.peek() != b'\n' && !self.is_at_end() {
+                        self.advance();
+                    }
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                b'/' if self.peek_next() == b'*' => {
+                    let (start, line, column) = (self.current, self.line, self.column());
+                    if !self.block_comment() {
+                        return Err(Token {
+                            t_type: TokenType::Error,
+                            token: "Unterminated block comment.",
+                            line,
+                            column,
+                            span: Span {
+                                start,
+                                end: start + 2,
+                            },
+                        });
                     }
                 }
-                _ => return,
+                _ => return Ok(()),
             }
         }
     }
 
-    fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+    /// Consume a `/* ... */` comment, which may contain nested block
+    /// comments. Returns false if the source ends before it is closed.
+    fn block_comment(&mut self) -> bool {
+        self.current += 2;
+        let mut depth = 1;
+        while depth > 0 {
+            match (self.peek(), self.peek_next()) {
+                _ if self.is_at_end() => return false,
+                (b'/', b'*') => {
+                    self.current += 2;
+                    depth += 1;
+                }
+                (b'*', b'/') => {
+                    self.current += 2;
+                    depth -= 1;
+                }
+                (b'\n', _) => {
+                    self.advance();
+                    self.new_line();
+                }
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+        true
+    }
+
+    /// Record that the character just consumed was a newline.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+        self.line_continuation_bytes = 0;
+    }
+
+    fn string(&mut self) -> Token<'src> {
+        // The first bad escape; scanning carries on to the closing quote so
+        // the rest of the string is not mistaken for code.
+        let mut invalid_escape = None;
+        while self.peek() != b'"' && !self.is_at_end() {
+            match self.advance() {
+                b'\n' => self.new_line(),
+                b'\\' => {
+                    let (start, line, column) = (self.current - 1, self.line, self.column() - 1);
+                    if !self.escape() && invalid_escape.is_none() {
+                        let span = Span {
+                            start,
+                            end: self.current,
+                        };
+                        invalid_escape = Some((line, column, span));
+                    }
+                }
+                _ => {}
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            self.error_token("Unterminated string.")
-        } else {
-            self.advance();
-            self.make_token(TokenType::String)
+            return self.error_token("Unterminated string.");
+        }
+        self.advance();
+
+        match invalid_escape {
+            Some((line, column, span)) => Token {
+                t_type: TokenType::Error,
+                token: "Invalid escape sequence.",
+                line,
+                column,
+                span,
+            },
+            None => self.make_token(TokenType::String),
         }
     }
 
-    fn number(&mut self) -> Token {
-        while is_digit(self.peek()) {
-            self.advance();
+    /// Consume an escape sequence up to, but not including, the first
+    /// character that cannot belong to it. The backslash has already been
+    /// consumed. Returns whether the sequence is valid.
+    fn escape(&mut self) -> bool {
+        match self.peek() {
+            b'n' | b't' | b'\\' | b'"' => {
+                self.advance();
+                true
+            }
+            b'x' => {
+                self.advance();
+                let digits = self.hex_digits(2);
+                digits.len() == 2 && u8::from_str_radix(digits, 16).is_ok_and(|b| b.is_ascii())
+            }
+            b'u' => {
+                self.advance();
+                if !self.match_char(b'{') {
+                    return false;
+                }
+                let digits = self.hex_digits(6);
+                self.match_char(b'}')
+                    && u32::from_str_radix(digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .is_some()
+            }
+            b'\n' | b'\0' => false,
+            _ => {
+                self.advance_char();
+                false
+            }
         }
+    }
 
-        if self.peek() == '.' && is_digit(self.peek_next()) {
+    /// Consume up to `max` hexadecimal digits.
+    fn hex_digits(&mut self, max: usize) -> &'src str {
+        let start = self.current;
+        while self.current - start < max && self.peek().is_ascii_hexdigit() {
             self.advance();
-            while is_digit(self.peek()) {
+        }
+        &self.source[start..self.current]
+    }
+
+    /// Column of the next character to be consumed.
+    fn column(&self) -> usize {
+        self.current - self.line_start - self.line_continuation_bytes + 1
+    }
+
+    /// Scan a number literal. The shape is only checked loosely here:
+    /// letters, digits and `_` directly after a literal are part of its
+    /// lexeme, so the parser's [`parse_number`] rejects `0xZZ` or `12ab` as
+    /// a whole instead of the scanner splitting them into two tokens.
+    fn number(&mut self) -> Token<'src> {
+        let has_radix_prefix =
+            self.byte_at(self.start) == b'0' && matches!(self.peek(), b'x' | b'b' | b'o');
+        if !has_radix_prefix {
+            self.digits();
+
+            if self.peek() == b'.' && is_digit(self.peek_next()) {
                 self.advance();
+                self.digits();
             }
+
+            if matches!(self.peek(), b'e' | b'E') {
+                self.advance();
+                if matches!(self.peek(), b'+' | b'-') && is_digit(self.peek_next()) {
+                    self.advance();
+                }
+            }
+        }
+
+        while is_alpha(self.peek()) || is_digit(self.peek()) {
+            self.advance();
         }
 
         self.make_token(TokenType::Number)
     }
 
-    fn identifier(&mut self) -> Token {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
+    /// Consume decimal digits and `_` separators.
+    fn digits(&mut self) {
+        while is_digit(self.peek()) || self.peek() == b'_' {
             self.advance();
         }
+    }
+
+    fn identifier(&mut self) -> Token<'src> {
+        loop {
+            let byte = self.peek();
+            if is_alpha(byte) || is_digit(byte) {
+                self.advance();
+            } else if !byte.is_ascii() && self.peek_char().is_xid_continue() {
+                self.advance_char();
+            } else {
+                break;
+            }
+        }
 
         self.make_token(self.identifier_type())
     }
 
     fn identifier_type(&self) -> TokenType {
-        match self.char_at(self.start) {
-            'a' => self.check_keyword(3, "and", TokenType::And),
-            'c' => self.check_keyword(5, "class", TokenType::Class),
-            'e' => self.check_keyword(4, "else", TokenType::Else),
-            'f' => {
+        match self.byte_at(self.start) {
+            b'a' => self.check_keyword(3, "and", TokenType::And),
+            b'c' => self.check_keyword(5, "class", TokenType::Class),
+            b'e' => self.check_keyword(4, "else", TokenType::Else),
+            b'f' => {
                 if self.current - self.start > 1 {
-                    match self.char_at(self.start + 1) {
-                        'a' => self.check_keyword(5, "false", TokenType::False),
-                        'o' => self.check_keyword(3, "for", TokenType::Or),
-                        'u' => self.check_keyword(3, "fun", TokenType::Fun),
+                    match self.byte_at(self.start + 1) {
+                        b'a' => self.check_keyword(5, "false", TokenType::False),
+                        b'o' => self.check_keyword(3, "for", TokenType::For),
+                        b'u' => self.check_keyword(3, "fun", TokenType::Fun),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(2, "if", TokenType::If),
-            'n' => self.check_keyword(3, "nil", TokenType::Nil),
-            'o' => self.check_keyword(2, "or", TokenType::Or),
-            'p' => self.check_keyword(5, "print", TokenType::Print),
-            'r' => self.check_keyword(6, "return", TokenType::Return),
-            's' => self.check_keyword(5, "super", TokenType::Super),
-            't' => {
+            b'i' => self.check_keyword(2, "if", TokenType::If),
+            b'n' => self.check_keyword(3, "nil", TokenType::Nil),
+            b'o' => self.check_keyword(2, "or", TokenType::Or),
+            b'p' => self.check_keyword(5, "print", TokenType::Print),
+            b'r' => self.check_keyword(6, "return", TokenType::Return),
+            b's' => self.check_keyword(5, "super", TokenType::Super),
+            b't' => {
                 if self.current - self.start > 1 {
-                    match self.char_at(self.start + 1) {
-                        'h' => self.check_keyword(4, "this", TokenType::This),
-                        'r' => self.check_keyword(4, "true", TokenType::True),
+                    match self.byte_at(self.start + 1) {
+                        b'h' => self.check_keyword(4, "this", TokenType::This),
+                        b'r' => self.check_keyword(4, "true", TokenType::True),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            'v' => self.check_keyword(3, "var", TokenType::Var),
-            'w' => self.check_keyword(5, "while", TokenType::While),
+            b'v' => self.check_keyword(3, "var", TokenType::Var),
+            b'w' => self.check_keyword(5, "while", TokenType::While),
             _ => TokenType::Identifier,
         }
     }
 
     fn check_keyword(&self, length: usize, rest: &str, t_type: TokenType) -> TokenType {
-        if self.current - self.start == length && self.slice() == rest.to_string() {
+        if self.current - self.start == length && self.slice() == rest {
             t_type
         } else {
             TokenType::Identifier
         }
     }
 
-    fn make_token(&self, token_type: TokenType) -> Token {
-        let token = &self.source[self.start..self.current];
+    fn make_token(&self, token_type: TokenType) -> Token<'src> {
         Token {
             t_type: token_type,
-            token: token.to_string(),
-            line: self.line,
+            token: self.slice(),
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
 
-    fn error_token(&self, message: &str) -> Token {
+    fn error_token(&self, message: &'static str) -> Token<'src> {
         Token {
             t_type: TokenType::Error,
-            token: message.to_string(),
-            line: self.line,
+            token: message,
+            line: self.start_line,
+            column: self.start_column,
+            span: self.span(),
         }
     }
 }
 
-fn is_digit(ch: char) -> bool {
-    ch >= '0' && ch <= '9'
+/// The value of a `TokenType::Number` lexeme, or `None` if it is malformed.
+///
+/// Accepts decimal literals with an optional fraction and exponent (`1.5e-3`)
+/// and integer literals in hex (`0xFF`), binary (`0b1010`) and octal (`0o17`).
+/// Digits may be separated by single underscores (`1_000`). Literals too
+/// large to be a finite `f64` are malformed too.
+pub fn parse_number(lexeme: &str) -> Option<f64> {
+    let radix = match lexeme.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _ => 10,
+    };
+    if radix != 10 {
+        let digits = digit_group(&lexeme[2..], radix)?;
+        return u128::from_str_radix(&digits, radix).ok().map(|n| n as f64);
+    }
+
+    let (mantissa, exponent) = match lexeme.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (lexeme, None),
+    };
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+
+    let mut normalized = digit_group(integer, 10)?;
+    if let Some(fraction) = fraction {
+        normalized.push('.');
+        normalized.push_str(&digit_group(fraction, 10)?);
+    }
+    if let Some(exponent) = exponent {
+        let (sign, digits) = match exponent.strip_prefix(['+', '-']) {
+            Some(digits) => (&exponent[..1], digits),
+            None => ("", exponent),
+        };
+        normalized.push('e');
+        normalized.push_str(sign);
+        normalized.push_str(&digit_group(digits, 10)?);
+    }
+    normalized.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// `group` with its `_` separators removed, if it is one or more digits in
+/// `radix` with each `_` between two digits.
+fn digit_group(group: &str, radix: u32) -> Option<String> {
+    let well_formed = !group.is_empty()
+        && !group.starts_with('_')
+        && !group.ends_with('_')
+        && !group.contains("__")
+        && group.chars().all(|c| c == '_' || c.is_digit(radix));
+    well_formed.then(|| group.replace('_', ""))
 }
 
-fn is_alpha(ch: char) -> bool {
-    (ch >= 'a' && ch <= 'z') || (ch >= 'A' && ch <= 'Z') || ch == '_'
+fn is_digit(byte: u8) -> bool {
+    byte.is_ascii_digit()
+}
+
+fn is_alpha(byte: u8) -> bool {
+    byte.is_ascii_alphabetic() || byte == b'_'
+}
+
+/// Whether `byte` continues a multi-byte UTF-8 character.
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every token up to, but not including, `Eof`.
+    fn scan(source: &str) -> Vec<Token<'_>> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_token();
+            if token.t_type == TokenType::Eof {
+                return tokens;
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn types(tokens: &[Token]) -> Vec<TokenType> {
+        tokens.iter().map(|token| token.t_type).collect()
+    }
+
+    #[test]
+    fn emoji_in_string_literal() {
+        let tokens = scan("\"héllo 🦀\" + 1");
+        assert_eq!(
+            types(&tokens),
+            [TokenType::String, TokenType::Plus, TokenType::Number]
+        );
+        assert_eq!(tokens[0].token, "\"héllo 🦀\"");
+        assert_eq!(tokens[0].span, Span { start: 0, end: 13 });
+        assert_eq!(tokens[1].column, 11);
+        assert_eq!(tokens[1].span, Span { start: 14, end: 15 });
+    }
+
+    #[test]
+    fn accented_identifiers() {
+        let tokens = scan("café naïve _über Straße δx x2é");
+        assert_eq!(types(&tokens), [TokenType::Identifier; 6]);
+        let lexemes: Vec<&str> = tokens.iter().map(|token| token.token).collect();
+        assert_eq!(lexemes, ["café", "naïve", "_über", "Straße", "δx", "x2é"]);
+        let columns: Vec<usize> = tokens.iter().map(|token| token.column).collect();
+        assert_eq!(columns, [1, 6, 12, 18, 25, 28]);
+    }
+
+    #[test]
+    fn keywords_are_not_matched_inside_unicode_identifiers() {
+        let tokens = scan("trueé nil");
+        assert_eq!(types(&tokens), [TokenType::Identifier, TokenType::Nil]);
+        assert_eq!(tokens[0].token, "trueé");
+    }
+
+    #[test]
+    fn emoji_outside_string_is_one_error() {
+        let tokens = scan("1 🦀 2");
+        assert_eq!(
+            types(&tokens),
+            [TokenType::Number, TokenType::Error, TokenType::Number]
+        );
+        assert_eq!(tokens[1].token, "Unexpected character.");
+        assert_eq!(tokens[1].span, Span { start: 2, end: 6 });
+        assert_eq!(tokens[2].column, 5);
+    }
+
+    #[test]
+    fn non_ascii_in_comment_keeps_positions() {
+        let tokens = scan("// ünïcödé ✓\n  1");
+        assert_eq!(types(&tokens), [TokenType::Number]);
+        assert_eq!((tokens[0].line, tokens[0].column), (2, 3));
+    }
+
+    #[test]
+    fn multi_line_string_resets_columns() {
+        let tokens = scan("\"ā\nēī\" 1");
+        assert_eq!(types(&tokens), [TokenType::String, TokenType::Number]);
+        assert_eq!((tokens[1].line, tokens[1].column), (2, 5));
+    }
+
+    #[test]
+    fn valid_escapes_scan_as_one_string() {
+        let tokens = scan(r#""a\nb\t\\ \"q\" \x41 \u{1F600} \u{e9}""#);
+        assert_eq!(types(&tokens), [TokenType::String]);
+    }
+
+    #[test]
+    fn invalid_escape_points_at_the_escape() {
+        let tokens = scan(r#"1 + "ok \q then" 2"#);
+        assert_eq!(
+            types(&tokens),
+            [
+                TokenType::Number,
+                TokenType::Plus,
+                TokenType::Error,
+                TokenType::Number
+            ]
+        );
+        assert_eq!(tokens[2].token, "Invalid escape sequence.");
+        assert_eq!(tokens[2].column, 9);
+        assert_eq!(tokens[2].span, Span { start: 8, end: 10 });
+    }
+
+    #[test]
+    fn malformed_numeric_escapes_are_rejected() {
+        for (source, span) in [
+            (r#""\x4""#, Span { start: 1, end: 4 }),
+            (r#""\x80""#, Span { start: 1, end: 5 }),
+            (r#""\u41""#, Span { start: 1, end: 3 }),
+            (r#""\u{}""#, Span { start: 1, end: 5 }),
+            (r#""\u{D800}""#, Span { start: 1, end: 9 }),
+            (r#""\u{110000}""#, Span { start: 1, end: 11 }),
+            (r#""\u{1F600""#, Span { start: 1, end: 9 }),
+        ] {
+            let tokens = scan(source);
+            assert_eq!(types(&tokens), [TokenType::Error], "{}", source);
+            assert_eq!(tokens[0].token, "Invalid escape sequence.", "{}", source);
+            assert_eq!(tokens[0].span, span, "{}", source);
+        }
+    }
+
+    #[test]
+    fn invalid_escape_after_multi_byte_characters() {
+        let tokens = scan("\"é\nüñ \\é\"");
+        assert_eq!(types(&tokens), [TokenType::Error]);
+        assert_eq!((tokens[0].line, tokens[0].column), (2, 4));
+        assert_eq!(tokens[0].span, Span { start: 9, end: 12 });
+    }
+
+    #[test]
+    fn unterminated_string_wins_over_bad_escape() {
+        let tokens = scan(r#""\q"#);
+        assert_eq!(tokens[0].token, "Unterminated string.");
+    }
+
+    #[test]
+    fn nested_block_comments_are_skipped() {
+        let tokens = scan("1 /* a /* b */ c */ + /**/ 2 /*/ x */");
+        assert_eq!(
+            types(&tokens),
+            [TokenType::Number, TokenType::Plus, TokenType::Number]
+        );
+        assert_eq!(tokens[2].column, 28);
+    }
+
+    #[test]
+    fn block_comments_count_lines() {
+        let tokens = scan("/* one\n /* twö\n */ three\n*/ 1");
+        assert_eq!(types(&tokens), [TokenType::Number]);
+        assert_eq!((tokens[0].line, tokens[0].column), (4, 4));
+    }
+
+    #[test]
+    fn unterminated_block_comment_reports_its_opening() {
+        let tokens = scan("1 +\n  /* outer /* inner */\n 2");
+        assert_eq!(
+            types(&tokens),
+            [TokenType::Number, TokenType::Plus, TokenType::Error]
+        );
+        assert_eq!(tokens[2].token, "Unterminated block comment.");
+        assert_eq!((tokens[2].line, tokens[2].column), (2, 3));
+        assert_eq!(tokens[2].span, Span { start: 6, end: 8 });
+    }
+
+    #[test]
+    fn number_literals_are_single_tokens() {
+        let tokens = scan("0xFF 0b1010 0o17 1_000 1.5e-3 2E+8 12ab 0xZZ 1.5e- 7.x");
+        let lexemes: Vec<&str> = tokens.iter().map(|token| token.token).collect();
+        assert_eq!(
+            lexemes,
+            [
+                "0xFF", "0b1010", "0o17", "1_000", "1.5e-3", "2E+8", "12ab", "0xZZ", "1.5e", "-",
+                "7", ".", "x"
+            ]
+        );
+    }
+
+    #[test]
+    fn well_formed_numbers_parse() {
+        for (lexeme, value) in [
+            ("0", 0.0),
+            ("123.45", 123.45),
+            ("0xFF", 255.0),
+            ("0xdead_BEEF", 3735928559.0),
+            ("0b1010", 10.0),
+            ("0b1111_0000", 240.0),
+            ("0o17", 15.0),
+            ("1_000_000", 1e6),
+            ("12.345_6", 12.3456),
+            ("1.5e-3", 1.5e-3),
+            ("2E+8", 2e8),
+            ("6e2_3", 6e23),
+            ("007", 7.0),
+        ] {
+            assert_eq!(parse_number(lexeme), Some(value), "{}", lexeme);
+        }
+    }
+
+    #[test]
+    fn malformed_numbers_do_not_parse() {
+        for lexeme in [
+            "12ab", "0x", "0xZZ", "0b102", "0o8", "0x_FF", "1__000", "1_", "1_.5", "1._5", "1e",
+            "1.5e", "1e+", "1e_5", "1e999", "0X1F",
+        ] {
+            assert_eq!(parse_number(lexeme), None, "{}", lexeme);
+        }
+    }
 }
@@ -0,0 +1,122 @@
+use crate::value::Value;
+use std::fmt;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEof,
+    InvalidUtf8,
+    InvalidValueTag(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not a lox image (bad magic header)"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported image version {}", version)
+            }
+            DecodeError::UnexpectedEof => write!(f, "image ended unexpectedly"),
+            DecodeError::InvalidUtf8 => write!(f, "image contains a name that is not UTF-8"),
+            DecodeError::InvalidValueTag(tag) => write!(f, "unknown value tag {}", tag),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub(crate) fn write_u8(out: &mut Vec<u8>, value: u8) {
+    out.push(value);
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_u32(out, value.len() as u32);
+    out.extend_from_slice(value.as_bytes());
+}
+
+pub(crate) fn write_value(out: &mut Vec<u8>, value: &Value) {
+    if let Some(n) = value.as_number() {
+        write_u8(out, TAG_NUMBER);
+        out.extend_from_slice(&n.to_bits().to_le_bytes());
+    } else {
+        match value.as_bool() {
+            Some(false) => write_u8(out, TAG_FALSE),
+            Some(true) => write_u8(out, TAG_TRUE),
+            None => write_u8(out, TAG_NIL),
+        }
+    }
+}
+
+/// Cursor over an encoded image. Every read fails with
+/// [`DecodeError::UnexpectedEof`] rather than panicking on short input.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub(crate) fn expect_header(&mut self, magic: &[u8], version: u8) -> Result<(), DecodeError> {
+        if self.read_bytes(magic.len()).ok() != Some(magic) {
+            return Err(DecodeError::BadMagic);
+        }
+        match self.read_u8()? {
+            v if v == version => Ok(()),
+            v => Err(DecodeError::UnsupportedVersion(v)),
+        }
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub(crate) fn read_string(&mut self) -> Result<String, DecodeError> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub(crate) fn read_value(&mut self) -> Result<Value, DecodeError> {
+        match self.read_u8()? {
+            TAG_NIL => Ok(Value::nil()),
+            TAG_FALSE => Ok(Value::bool(false)),
+            TAG_TRUE => Ok(Value::bool(true)),
+            TAG_NUMBER => {
+                let bytes = self.read_bytes(8)?;
+                Ok(Value::number(f64::from_bits(u64::from_le_bytes(
+                    bytes.try_into().unwrap(),
+                ))))
+            }
+            tag => Err(DecodeError::InvalidValueTag(tag)),
+        }
+    }
+}
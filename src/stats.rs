@@ -0,0 +1,99 @@
+use crate::chunk::OpCode;
+use std::cmp::Reverse;
+
+/// Runtime counts of how often each opcode directly follows another, used
+/// to pick which pairs are worth fusing into superinstructions.
+#[derive(Debug, Clone)]
+pub struct PairStats {
+    counts: Vec<u64>,
+    previous: Option<OpCode>,
+}
+
+impl Default for PairStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PairStats {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; OpCode::ALL.len() * OpCode::ALL.len()],
+            previous: None,
+        }
+    }
+
+    pub(crate) fn record(&mut self, op_code: OpCode) {
+        if let Some(previous) = self.previous {
+            self.counts[previous as usize * OpCode::ALL.len() + op_code as usize] += 1;
+        }
+        self.previous = Some(op_code);
+    }
+
+    /// Forget the previous opcode, so no pair spans two different scripts.
+    pub(crate) fn end_sequence(&mut self) {
+        self.previous = None;
+    }
+
+    /// Every pair that was executed with its count, most frequent first.
+    pub fn pairs(&self) -> Vec<(OpCode, OpCode, u64)> {
+        let width = OpCode::ALL.len();
+        let mut pairs: Vec<_> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| (OpCode::ALL[i / width], OpCode::ALL[i % width], *count))
+            .collect();
+        pairs.sort_by_key(|&(_, _, count)| Reverse(count));
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Chunk, Position};
+    use crate::vm::{RunStatus, Vm};
+
+    fn run(vm: &mut Vm, op_codes: &[OpCode]) {
+        let mut chunk = Chunk::new();
+        for op_code in op_codes {
+            chunk.write_chunk(*op_code, Position::default());
+        }
+        vm.load_chunk(chunk).unwrap();
+        assert!(matches!(vm.run_for(usize::MAX), RunStatus::Finished(_)));
+    }
+
+    #[test]
+    fn pairs_are_counted_within_each_script() {
+        let mut vm = Vm::new();
+        run(&mut vm, &[OpCode::OpTrue, OpCode::OpReturn]);
+        assert!(vm.stats().is_none());
+
+        vm.enable_stats();
+        run(
+            &mut vm,
+            &[
+                OpCode::OpTrue,
+                OpCode::OpNot,
+                OpCode::OpNot,
+                OpCode::OpNot,
+                OpCode::OpReturn,
+            ],
+        );
+        run(&mut vm, &[OpCode::OpFalse, OpCode::OpReturn]);
+
+        // Ties keep opcode order. No (OpReturn, OpFalse) pair joins the two
+        // scripts.
+        assert_eq!(
+            vm.stats().unwrap().pairs(),
+            [
+                (OpCode::OpNot, OpCode::OpNot, 2),
+                (OpCode::OpTrue, OpCode::OpNot, 1),
+                (OpCode::OpFalse, OpCode::OpReturn, 1),
+                (OpCode::OpNot, OpCode::OpReturn, 1),
+            ]
+        );
+    }
+}
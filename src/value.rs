@@ -1,3 +1,12 @@
+//! Lox values.
+//!
+//! By default a `Value` is a plain Rust enum. With the `nan-boxing` feature it
+//! is instead a single `u64`: numbers are stored as their IEEE 754 bits and
+//! every other value hides in the payload of a quiet NaN. Code outside this
+//! module goes through the constructors and accessors below, which both
+//! representations provide, so it compiles unchanged either way.
+
+#[cfg(not(feature = "nan-boxing"))]
 #[derive(Debug, Clone, Default, PartialEq)]
 pub enum Value {
     Bool(bool),
@@ -7,19 +16,35 @@ pub enum Value {
     Number(f64),
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
-    pub fn is_number(&self) -> bool {
+    pub fn nil() -> Self {
+        Value::Nil
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Value::Bool(b)
+    }
+
+    pub fn number(n: f64) -> Self {
+        Value::Number(n)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
         match self {
-            Value::Number(_) => true,
-            _ => false,
+            Value::Bool(b) => Some(*b),
+            _ => None,
         }
     }
 
-    pub fn is_falsy(&self) -> bool {
+    pub fn as_number(&self) -> Option<f64> {
         match self {
-            Value::Nil => true,
-            Value::Bool(b) => !b,
-            _ => false,
+            Value::Number(n) => Some(*n),
+            _ => None,
         }
     }
 
@@ -27,3 +52,193 @@ impl Value {
         self == other
     }
 }
+
+/// Not `Copy`, like the enum representation, so the rest of the crate is
+/// written against the same API whichever one is compiled in.
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    /// Exponent bits plus the quiet NaN bit and the bit above it. A `u64` with
+    /// all of these set is not a number but a boxed non-number value.
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    pub fn nil() -> Self {
+        Value(Self::QNAN | Self::TAG_NIL)
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Value(Self::QNAN | if b { Self::TAG_TRUE } else { Self::TAG_FALSE })
+    }
+
+    /// NaNs are canonicalized so that no NaN payload, including one read back
+    /// from a bytecode file, can be mistaken for a boxed value.
+    pub fn number(n: f64) -> Self {
+        if n.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(n.to_bits())
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == Self::nil().0
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            bits if bits == Self::QNAN | Self::TAG_TRUE => Some(true),
+            bits if bits == Self::QNAN | Self::TAG_FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if self.0 & Self::QNAN == Self::QNAN {
+            None
+        } else {
+            Some(f64::from_bits(self.0))
+        }
+    }
+
+    pub fn equals(&self, other: &Self) -> bool {
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl Default for Value {
+    fn default() -> Self {
+        Self::nil()
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.equals(other)
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(b) = self.as_bool() {
+            write!(f, "Bool({:?})", b)
+        } else if let Some(n) = self.as_number() {
+            write!(f, "Number({:?})", n)
+        } else {
+            write!(f, "Nil")
+        }
+    }
+}
+
+/// Identity of a value for constant pool deduplication.
+///
+/// Unlike [`Value::equals`], numbers are compared by bit pattern, so `0.0` and
+/// `-0.0` stay distinct constants while a NaN constant is shared with itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueKey {
+    Bool(bool),
+    Nil,
+    Number(u64),
+}
+
+impl Value {
+    pub fn key(&self) -> ValueKey {
+        if let Some(b) = self.as_bool() {
+            ValueKey::Bool(b)
+        } else if let Some(n) = self.as_number() {
+            ValueKey::Number(n.to_bits())
+        } else {
+            ValueKey::Nil
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.as_number().is_some()
+    }
+
+    pub fn is_falsy(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One of each kind of value, with the numbers that need care.
+    fn samples() -> Vec<Value> {
+        vec![
+            Value::nil(),
+            Value::bool(false),
+            Value::bool(true),
+            Value::number(0.0),
+            Value::number(-0.0),
+            Value::number(1.5),
+            Value::number(f64::INFINITY),
+            Value::number(f64::NEG_INFINITY),
+            Value::number(f64::NAN),
+        ]
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn boxed_value_is_one_word() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn nan_payloads_stay_numbers() {
+        for bits in [
+            0x7ffc_0000_0000_0001,
+            0xfffc_0000_0000_0003,
+            0x7ff8_0000_0000_0002,
+        ] {
+            let value = Value::number(f64::from_bits(bits));
+            assert!(value.as_number().is_some_and(f64::is_nan), "{:#x}", bits);
+            assert!(!value.is_nil() && value.as_bool().is_none(), "{:#x}", bits);
+            assert!(matches!(value.key(), ValueKey::Number(_)), "{:#x}", bits);
+        }
+    }
+
+    #[test]
+    fn special_numbers_survive() {
+        for n in [-0.0, f64::INFINITY, f64::NEG_INFINITY] {
+            let value = Value::number(n);
+            assert_eq!(value.as_number().map(f64::to_bits), Some(n.to_bits()));
+            assert_eq!(value.key(), ValueKey::Number(n.to_bits()));
+        }
+        assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+        assert_ne!(Value::number(0.0).key(), Value::number(-0.0).key());
+    }
+
+    #[test]
+    fn equality_and_truthiness_follow_lox() {
+        let samples = samples();
+        // Pairs of indexes into `samples` that are equal, besides each value
+        // with itself (except NaN, which is unequal to itself).
+        let equal_pairs = [(3, 4), (4, 3)];
+        for (i, a) in samples.iter().enumerate() {
+            for (j, b) in samples.iter().enumerate() {
+                let expected = (i == j && i != 8) || equal_pairs.contains(&(i, j));
+                assert_eq!(a.equals(b), expected, "{:?} == {:?}", a, b);
+            }
+        }
+
+        let falsy: Vec<bool> = samples.iter().map(Value::is_falsy).collect();
+        assert_eq!(
+            falsy,
+            [true, true, false, false, false, false, false, false, false]
+        );
+    }
+}
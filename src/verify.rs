@@ -0,0 +1,282 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::register::MAX_REGISTERS;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    UnknownOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    TruncatedInstruction {
+        offset: usize,
+    },
+    ConstantOutOfRange {
+        offset: usize,
+        index: usize,
+    },
+    StackUnderflow {
+        offset: usize,
+    },
+    BadLineTable,
+    /// The stack grows deeper than the register backend has registers for.
+    TooManyRegisters {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::UnknownOpcode { offset, byte } => {
+                write!(f, "unknown opcode {} at offset {}", byte, offset)
+            }
+            VerifyError::TruncatedInstruction { offset } => {
+                write!(f, "instruction at offset {} is missing operands", offset)
+            }
+            VerifyError::ConstantOutOfRange { offset, index } => write!(
+                f,
+                "constant index {} at offset {} is out of range",
+                index, offset
+            ),
+            VerifyError::StackUnderflow { offset } => {
+                write!(f, "instruction at offset {} pops an empty stack", offset)
+            }
+            VerifyError::BadLineTable => write!(f, "line table does not cover the code"),
+            VerifyError::TooManyRegisters { offset } => write!(
+                f,
+                "instruction at offset {} needs more than {} registers",
+                offset, MAX_REGISTERS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Statically check a chunk that did not come from our own compiler.
+///
+/// Walks the code one instruction at a time, so every offset the VM can
+/// reach is an instruction boundary, operands stay in bounds, and the value
+/// stack never underflows. Code is straight-line today; jump targets will
+/// have to be checked against the same boundaries once jumps exist.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    verify_lines(chunk)?;
+
+    let mut offset = 0;
+    let mut depth: usize = 0;
+    while offset < chunk.code.len() {
+        let byte = chunk.code[offset];
+        let op_code = OpCode::from_byte(byte).ok_or(VerifyError::UnknownOpcode { offset, byte })?;
+
+        let next = offset + 1 + op_code.operand_len();
+        if next > chunk.code.len() {
+            return Err(VerifyError::TruncatedInstruction { offset });
+        }
+
+        if matches!(
+            op_code,
+            OpCode::OpConstant | OpCode::OpConstantLong | OpCode::OpConstantAdd
+        ) {
+            let index = chunk.read_constant_index(offset);
+            if index >= chunk.constants.len() {
+                return Err(VerifyError::ConstantOutOfRange { offset, index });
+            }
+        }
+
+        let (pops, pushes) = stack_effect(op_code);
+        depth = depth
+            .checked_sub(pops)
+            .ok_or(VerifyError::StackUnderflow { offset })?
+            + pushes;
+
+        offset = next;
+    }
+
+    Ok(())
+}
+
+/// The line and column tables must each start at offset 0 and list runs in
+/// increasing order, and every byte of code needs a source offset, so every
+/// byte maps to a position for error reporting.
+fn verify_lines(chunk: &Chunk) -> Result<(), VerifyError> {
+    if chunk.source_offsets.len() != chunk.code.len() {
+        return Err(VerifyError::BadLineTable);
+    }
+    if chunk.code.is_empty() {
+        return Ok(());
+    }
+
+    let line_starts: Vec<u32> = chunk.lines.iter().map(|run| run.start).collect();
+    let column_starts: Vec<u32> = chunk.columns.iter().map(|run| run.start).collect();
+    if covers_code(&line_starts, chunk.code.len()) && covers_code(&column_starts, chunk.code.len())
+    {
+        Ok(())
+    } else {
+        Err(VerifyError::BadLineTable)
+    }
+}
+
+/// Whether runs beginning at `starts` cover `code_len` bytes of code.
+fn covers_code(starts: &[u32], code_len: usize) -> bool {
+    starts.first() == Some(&0)
+        && starts.windows(2).all(|pair| pair[0] < pair[1])
+        && starts
+            .last()
+            .is_some_and(|&start| (start as usize) < code_len)
+}
+
+/// Number of values an instruction pops and then pushes.
+fn stack_effect(op_code: OpCode) -> (usize, usize) {
+    match op_code {
+        OpCode::OpConstant
+        | OpCode::OpConstantLong
+        | OpCode::OpNil
+        | OpCode::OpTrue
+        | OpCode::OpFalse => (0, 1),
+        OpCode::OpNegate | OpCode::OpNot | OpCode::OpConstantAdd => (1, 1),
+        OpCode::OpAdd
+        | OpCode::OpAddNumber
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpEqual
+        | OpCode::OpGreater
+        | OpCode::OpLess
+        | OpCode::OpNotEqual
+        | OpCode::OpGreaterEqual
+        | OpCode::OpLessEqual => (2, 1),
+        OpCode::OpReturn => (1, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ColumnRun, LineRun, Position};
+    use crate::compile::Parser;
+    use crate::value::Value;
+
+    /// A chunk holding `code` verbatim, all on one line, with one constant.
+    fn raw_chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::number(1.0));
+        for byte in code {
+            chunk.write_chunk(*byte, Position::default());
+        }
+        chunk
+    }
+
+    fn run(start: u32) -> LineRun {
+        LineRun { start, line: 1 }
+    }
+
+    #[test]
+    fn compiled_chunk_passes() {
+        let chunk = Parser::new("(1 + 2) * -true < 4 == !nil")
+            .compile()
+            .unwrap();
+        assert_eq!(verify(&chunk), Ok(()));
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let chunk = raw_chunk(&[OpCode::OpNil as u8, 0xff]);
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::UnknownOpcode {
+                offset: 1,
+                byte: 0xff
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_constant_long() {
+        let chunk = raw_chunk(&[OpCode::OpNil as u8, OpCode::OpConstantLong as u8, 0, 0]);
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::TruncatedInstruction { offset: 1 })
+        );
+    }
+
+    #[test]
+    fn constant_out_of_range() {
+        let chunk = raw_chunk(&[OpCode::OpConstant as u8, 0, OpCode::OpConstant as u8, 1]);
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::ConstantOutOfRange {
+                offset: 2,
+                index: 1
+            })
+        );
+
+        let chunk = raw_chunk(&[OpCode::OpConstantLong as u8, 0, 1, 0]);
+        assert_eq!(
+            verify(&chunk),
+            Err(VerifyError::ConstantOutOfRange {
+                offset: 0,
+                index: 256
+            })
+        );
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let cases: [&[u8]; 3] = [
+            &[OpCode::OpNil as u8, OpCode::OpAdd as u8],
+            &[
+                OpCode::OpNil as u8,
+                OpCode::OpReturn as u8,
+                OpCode::OpReturn as u8,
+            ],
+            &[OpCode::OpConstantAdd as u8, 0],
+        ];
+        let offsets = [1, 2, 0];
+        for (code, offset) in cases.into_iter().zip(offsets) {
+            assert_eq!(
+                verify(&raw_chunk(code)),
+                Err(VerifyError::StackUnderflow { offset }),
+                "{:?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn bad_line_table() {
+        let code = [
+            OpCode::OpNil as u8,
+            OpCode::OpNil as u8,
+            OpCode::OpAdd as u8,
+        ];
+        let tables = [
+            vec![],
+            vec![run(1)],
+            vec![run(0), run(2), run(1)],
+            vec![run(0), run(3)],
+        ];
+        for lines in tables {
+            let mut chunk = raw_chunk(&code);
+            chunk.lines = lines.clone();
+            assert_eq!(
+                verify(&chunk),
+                Err(VerifyError::BadLineTable),
+                "{:?}",
+                lines
+            );
+        }
+
+        let mut chunk = raw_chunk(&code);
+        chunk.lines = vec![run(0), run(1), run(2)];
+        assert_eq!(verify(&chunk), Ok(()));
+
+        let mut chunk = raw_chunk(&code);
+        chunk.columns = vec![ColumnRun { start: 1, base: 0 }];
+        assert_eq!(verify(&chunk), Err(VerifyError::BadLineTable));
+
+        let mut chunk = raw_chunk(&code);
+        chunk.source_offsets.pop();
+        assert_eq!(verify(&chunk), Err(VerifyError::BadLineTable));
+    }
+}
@@ -1,12 +1,39 @@
 use crate::chunk::{Chunk, OpCode};
 use crate::compile::Parser;
 use crate::debug::print_value;
+use crate::register::{lower, RegOpCode, RegisterChunk, CONSTANT};
+use crate::stats::PairStats;
 use crate::value::Value;
+use crate::verify::{verify, VerifyError};
+use std::cmp::Ordering;
+
+/// Initial capacity of the value stack, so typical scripts never reallocate it.
+/// This is not a limit: the stack grows past it as deep as a script needs.
+const STACK_INITIAL_CAPACITY: usize = 256;
 
 pub struct Vm {
     chunk: Chunk,
     stack: Vec<Value>,
     ip: usize,
+    /// The runtime error the loaded script stopped on, as it was reported.
+    /// Once set, the script is never resumed on the stack the error left.
+    error: Option<String>,
+    /// The value the loaded script finished with, handed back again by later
+    /// calls instead of running whatever code follows its `OpReturn`.
+    finished: Option<Value>,
+    stats: Option<PairStats>,
+    backend: Backend,
+}
+
+/// Which instruction set scripts are executed as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Stack bytecode, as compiled and as stored in `.loxc` files.
+    #[default]
+    Stack,
+    /// Three-address code lowered from the stack bytecode at load time; see
+    /// [`crate::register`]. The value stack serves as the register file.
+    Register,
 }
 
 pub enum InterpretResult {
@@ -15,139 +42,626 @@ pub enum InterpretResult {
     RuntimeError,
 }
 
-enum BinaryOp {
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Greater,
-    Less,
+/// Where a script stands after [`Vm::run_for`] hands control back to the host.
+#[derive(Debug)]
+pub enum RunStatus {
+    /// The script ran to completion and produced this value.
+    Finished(Value),
+    /// The instruction budget ran out; call `run_for` again to resume.
+    Suspended,
+    /// The script stopped on a runtime error, which has already been reported.
+    RuntimeError,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
         Self {
             chunk: Chunk::new(),
-            // chunk,
-            stack: vec![],
+            stack: Vec::with_capacity(STACK_INITIAL_CAPACITY),
             ip: 0,
+            error: None,
+            finished: None,
+            stats: None,
+            backend: Backend::Stack,
         }
     }
 
     pub fn interpret_src(&mut self, source: &str) -> InterpretResult {
-        let mut parser = Parser::new(source);
-        let chunk = parser.compile();
+        match self.load_src(source) {
+            InterpretResult::Ok => self.run(),
+            result => result,
+        }
+    }
+
+    /// Like [`Vm::interpret_src`], naming `file` as the source in errors.
+    pub fn interpret_file(&mut self, file: &str, source: &str) -> InterpretResult {
+        match self.load_file(file, source) {
+            InterpretResult::Ok => self.run(),
+            result => result,
+        }
+    }
+
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> InterpretResult {
+        match self.load_chunk(chunk) {
+            Ok(()) => self.run(),
+            Err(err) => {
+                eprintln!("Invalid bytecode: {}.", err);
+                InterpretResult::CompileError
+            }
+        }
+    }
+
+    /// Compile `source` and make it the script to execute, without running it.
+    pub fn load_src(&mut self, source: &str) -> InterpretResult {
+        self.load_parsed(Parser::new(source))
+    }
+
+    /// Like [`Vm::load_src`], naming `file` as the source in errors.
+    pub fn load_file(&mut self, file: &str, source: &str) -> InterpretResult {
+        self.load_parsed(Parser::new(source).with_file(file))
+    }
+
+    fn load_parsed(&mut self, mut parser: Parser) -> InterpretResult {
+        let loaded = match self.backend {
+            Backend::Stack => parser.compile().map(|chunk| self.set_chunk(chunk)),
+            Backend::Register => parser
+                .compile_registers()
+                .map(|lowered| self.set_register_chunk(lowered)),
+        };
+        match loaded {
+            Some(()) => InterpretResult::Ok,
+            None => InterpretResult::CompileError,
+        }
+    }
+
+    /// Make `chunk` the script to execute, starting from its first instruction.
+    ///
+    /// The chunk may come from an untrusted source such as a `.loxc` file, so
+    /// it is verified first and rejected if running it could crash the VM.
+    pub fn load_chunk(&mut self, chunk: Chunk) -> Result<(), VerifyError> {
+        verify(&chunk)?;
+        match self.backend {
+            Backend::Stack => self.set_chunk(chunk),
+            Backend::Register => self.set_register_chunk(lower(&chunk)?),
+        }
+        Ok(())
+    }
+
+    /// Choose the backend that scripts loaded from now on are executed with.
+    /// The currently loaded script, if any, is unloaded.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.set_chunk(Chunk::new());
+    }
+
+    fn set_chunk(&mut self, chunk: Chunk) {
+        if let Some(stats) = &mut self.stats {
+            stats.end_sequence();
+        }
         self.chunk = chunk;
-        self.run()
+        self.stack.clear();
+        self.ip = 0;
+        self.error = None;
+        self.finished = None;
+    }
+
+    fn set_register_chunk(&mut self, lowered: RegisterChunk) {
+        self.set_chunk(lowered.chunk);
+        self.stack.resize(lowered.registers, Value::nil());
+    }
+
+    /// Start counting executed opcode pairs; see [`Vm::stats`]. Only the
+    /// stack backend records them.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(PairStats::new);
+    }
+
+    pub fn stats(&self) -> Option<&PairStats> {
+        self.stats.as_ref()
     }
 
     pub fn run(&mut self) -> InterpretResult {
-        for c in &self.chunk.code.clone() {
-            self.ip += 1;
-            let result = match c {
-                OpCode::OpConstant { index } => {
-                    let constant = self.read_constant(*index);
-                    self.stack.push(constant);
-                    InterpretResult::Ok
-                }
-                OpCode::OpReturn => {
-                    print_value(&self.stack.pop().unwrap_or_default());
+        loop {
+            match self.run_for(usize::MAX) {
+                RunStatus::Finished(value) => {
+                    print_value(&value);
                     println!();
-                    InterpretResult::Ok
+                    return InterpretResult::Ok;
                 }
-                OpCode::OpNegate => match self.peek(0) {
-                    Value::Number(n) => {
-                        self.stack.pop();
-                        self.stack.push(Value::Number(-n));
-                        InterpretResult::Ok
+                RunStatus::Suspended => {}
+                RunStatus::RuntimeError => return InterpretResult::RuntimeError,
+            }
+        }
+    }
+
+    /// Execute at most `n_instructions` instructions of the loaded script.
+    ///
+    /// A suspended script keeps its stack and instruction pointer, so the next
+    /// call picks up exactly where this one stopped. Once a script has
+    /// finished or stopped on a runtime error, every call returns the same
+    /// status again, without executing anything, until a new script is loaded.
+    pub fn run_for(&mut self, n_instructions: usize) -> RunStatus {
+        if self.error.is_some() {
+            return RunStatus::RuntimeError;
+        }
+        if let Some(value) = &self.finished {
+            return RunStatus::Finished(value.clone());
+        }
+
+        let status = match self.backend {
+            Backend::Stack => self.run_stack(n_instructions),
+            Backend::Register => self.run_registers(n_instructions),
+        };
+        if let RunStatus::Finished(value) = &status {
+            self.finished = Some(value.clone());
+        }
+        status
+    }
+
+    fn run_stack(&mut self, n_instructions: usize) -> RunStatus {
+        let code = self.chunk.code.as_mut_slice();
+        let constants = &self.chunk.constants;
+        let stack = &mut self.stack;
+        let mut stats = self.stats.as_mut();
+        let mut ip = self.ip;
+
+        // Runtime errors need the line table, so they break out of the loop
+        // with the failing offset and are reported once `code` is released.
+        let mut remaining = n_instructions;
+        let result = loop {
+            if remaining == 0 {
+                break Ok(RunStatus::Suspended);
+            }
+            remaining -= 1;
+
+            let Some(&byte) = code.get(ip) else {
+                break Ok(RunStatus::Finished(stack.pop().unwrap_or_default()));
+            };
+            let offset = ip;
+            ip += 1;
+
+            let op_code = OpCode::from_byte(byte);
+            if let (Some(stats), Some(op_code)) = (&mut stats, op_code) {
+                stats.record(op_code);
+            }
+
+            match op_code {
+                Some(OpCode::OpConstant) => {
+                    let index = code[ip] as usize;
+                    ip += 1;
+                    stack.push(constants[index].clone());
+                }
+                Some(OpCode::OpConstantLong) => {
+                    let index = u32::from_le_bytes([code[ip], code[ip + 1], code[ip + 2], 0]);
+                    ip += 3;
+                    stack.push(constants[index as usize].clone());
+                }
+                Some(OpCode::OpReturn) => {
+                    break Ok(RunStatus::Finished(stack.pop().unwrap_or_default()));
+                }
+                Some(OpCode::OpNegate) => {
+                    let top = stack.last_mut().unwrap();
+                    match top.as_number() {
+                        Some(n) => *top = Value::number(-n),
+                        None => break Err((offset, "Operand must be a number.".to_string())),
+                    }
+                }
+                Some(OpCode::OpNil) => stack.push(Value::nil()),
+                Some(OpCode::OpTrue) => stack.push(Value::bool(true)),
+                Some(OpCode::OpFalse) => stack.push(Value::bool(false)),
+                Some(OpCode::OpNot) => {
+                    let top = stack.last_mut().unwrap();
+                    *top = Value::bool(top.is_falsy());
+                }
+                Some(OpCode::OpEqual) => {
+                    let b = stack.pop().unwrap();
+                    let top = stack.last_mut().unwrap();
+                    *top = Value::bool(top.equals(&b));
+                }
+                Some(OpCode::OpNotEqual) => {
+                    let b = stack.pop().unwrap();
+                    let top = stack.last_mut().unwrap();
+                    *top = Value::bool(!top.equals(&b));
+                }
+                Some(OpCode::OpAdd) => {
+                    // Quicken: once this site has added two numbers, assume it
+                    // keeps doing so and skip straight to the numeric add.
+                    if both_numbers(stack) {
+                        code[offset] = OpCode::OpAddNumber.into();
                     }
-                    _ => {
-                        self.runtime_error("Operand must be a number.");
-                        InterpretResult::RuntimeError
+                    if !binary_op(stack, OpCode::OpAdd) {
+                        break Err((offset, "Operands must be numbers.".to_string()));
+                    }
+                }
+                Some(OpCode::OpAddNumber) => {
+                    let len = stack.len();
+                    match (stack[len - 2].as_number(), stack[len - 1].as_number()) {
+                        (Some(a), Some(b)) => {
+                            stack.pop();
+                            stack[len - 2] = Value::number(a + b);
+                        }
+                        _ => {
+                            // The guess no longer holds: de-optimize this site
+                            // back to the generic instruction and run that.
+                            code[offset] = OpCode::OpAdd.into();
+                            if !binary_op(stack, OpCode::OpAdd) {
+                                break Err((offset, "Operands must be numbers.".to_string()));
+                            }
+                        }
+                    }
+                }
+                Some(OpCode::OpConstantAdd) => {
+                    let constant = &constants[code[ip] as usize];
+                    ip += 1;
+                    let top = stack.last_mut().unwrap();
+                    match (top.as_number(), constant.as_number()) {
+                        (Some(a), Some(b)) => *top = Value::number(a + b),
+                        _ => break Err((offset, "Operands must be numbers.".to_string())),
                     }
-                },
-                OpCode::OpAdd => self.binary_op(BinaryOp::Add),
-                OpCode::OpSubtract => self.binary_op(BinaryOp::Subtract),
-                OpCode::OpMultiply => self.binary_op(BinaryOp::Multiply),
-                OpCode::OpDivide => self.binary_op(BinaryOp::Divide),
-                OpCode::OpNil => {
-                    self.stack.push(Value::Nil);
-                    InterpretResult::Ok
-                }
-                OpCode::OpTrue => {
-                    self.stack.push(Value::Bool(true));
-                    InterpretResult::Ok
-                }
-                OpCode::OpFalse => {
-                    self.stack.push(Value::Bool(false));
-                    InterpretResult::Ok
-                }
-                OpCode::OpNot => {
-                    let value = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(value.is_falsy()));
-                    InterpretResult::Ok
-                }
-                OpCode::OpEqual => {
-                    let a = self.stack.pop().unwrap();
-                    let b = self.stack.pop().unwrap();
-
-                    self.stack.push(Value::Bool(a.equals(&b)));
-                    InterpretResult::Ok
-                }
-                OpCode::OpGreater => self.binary_op(BinaryOp::Greater),
-                OpCode::OpLess => self.binary_op(BinaryOp::Less),
+                }
+                Some(
+                    op_code @ (OpCode::OpSubtract
+                    | OpCode::OpMultiply
+                    | OpCode::OpDivide
+                    | OpCode::OpGreater
+                    | OpCode::OpLess
+                    | OpCode::OpGreaterEqual
+                    | OpCode::OpLessEqual),
+                ) => {
+                    if !binary_op(stack, op_code) {
+                        break Err((offset, "Operands must be numbers.".to_string()));
+                    }
+                }
+                None => break Err((offset, format!("Unknown opcode {}.", byte))),
+            }
+        };
+
+        self.ip = ip;
+        match result {
+            Ok(status) => status,
+            Err((offset, message)) => {
+                self.runtime_error(offset, &message);
+                RunStatus::RuntimeError
+            }
+        }
+    }
+
+    fn run_registers(&mut self, n_instructions: usize) -> RunStatus {
+        let code = self.chunk.code.as_slice();
+        let constants = &self.chunk.constants;
+        let registers = self.stack.as_mut_slice();
+        let mut ip = self.ip;
+
+        let mut remaining = n_instructions;
+        let result = loop {
+            if remaining == 0 {
+                break Ok(RunStatus::Suspended);
+            }
+            remaining -= 1;
+
+            let Some(&byte) = code.get(ip) else {
+                break Ok(RunStatus::Finished(Value::nil()));
+            };
+            let offset = ip;
+            let Some(op_code) = RegOpCode::from_byte(byte) else {
+                break Err((offset, format!("Unknown opcode {}.", byte)));
             };
+            ip += 1 + op_code.operand_len();
 
-            match result {
-                InterpretResult::Ok => {}
-                _ => {
-                    return result;
+            let operand = |index: usize| read_u16(code, offset + 1 + 2 * index);
+            let dst = operand(0) as usize;
+            match op_code {
+                RegOpCode::LoadConstant => {
+                    let k = &code[offset + 3..offset + 6];
+                    let index = u32::from_le_bytes([k[0], k[1], k[2], 0]);
+                    registers[dst] = constants[index as usize].clone();
+                }
+                RegOpCode::LoadNil => registers[dst] = Value::nil(),
+                RegOpCode::LoadTrue => registers[dst] = Value::bool(true),
+                RegOpCode::LoadFalse => registers[dst] = Value::bool(false),
+                RegOpCode::Negate => {
+                    match read_operand(registers, constants, operand(1)).as_number() {
+                        Some(n) => registers[dst] = Value::number(-n),
+                        None => break Err((offset, "Operand must be a number.".to_string())),
+                    }
+                }
+                RegOpCode::Not => {
+                    let a = read_operand(registers, constants, operand(1));
+                    registers[dst] = Value::bool(a.is_falsy());
+                }
+                RegOpCode::Equal | RegOpCode::NotEqual => {
+                    let a = read_operand(registers, constants, operand(1));
+                    let b = read_operand(registers, constants, operand(2));
+                    let equal = a.equals(b);
+                    registers[dst] = Value::bool(equal == (op_code == RegOpCode::Equal));
                 }
+                RegOpCode::Return => {
+                    let value = read_operand(registers, constants, operand(0));
+                    break Ok(RunStatus::Finished(value.clone()));
+                }
+                RegOpCode::Add
+                | RegOpCode::Subtract
+                | RegOpCode::Multiply
+                | RegOpCode::Divide
+                | RegOpCode::Greater
+                | RegOpCode::Less
+                | RegOpCode::GreaterEqual
+                | RegOpCode::LessEqual => {
+                    let a = read_operand(registers, constants, operand(1));
+                    let b = read_operand(registers, constants, operand(2));
+                    let (Some(a), Some(b)) = (a.as_number(), b.as_number()) else {
+                        break Err((offset, "Operands must be numbers.".to_string()));
+                    };
+                    let op_code = op_code.numeric_op_code().unwrap();
+                    registers[dst] = numeric_op(op_code, a, b);
+                }
+            }
+        };
+
+        self.ip = ip;
+        match result {
+            Ok(status) => status,
+            Err((offset, message)) => {
+                self.runtime_error(offset, &message);
+                RunStatus::RuntimeError
             }
         }
+    }
+
+    fn runtime_error(&mut self, offset: usize, message: &str) {
+        let position = self.chunk.get_position(offset);
+        let error = format!(
+            "{}:{}:{}: Error: {}",
+            self.chunk.file, position.line, position.column, message
+        );
+        eprintln!("{}", error);
+        self.error = Some(error);
+    }
+}
 
-        return InterpretResult::CompileError;
+fn both_numbers(stack: &[Value]) -> bool {
+    matches!(stack, [.., a, b] if a.is_number() && b.is_number())
+}
+
+/// Replace the top two stack values with the result of the numeric binary
+/// instruction `op_code`, in place. Returns false, leaving the stack
+/// untouched, if either operand is not a number.
+#[inline]
+fn binary_op(stack: &mut Vec<Value>, op_code: OpCode) -> bool {
+    let [.., a, b] = stack.as_slice() else {
+        return false;
+    };
+    let (Some(a), Some(b)) = (a.as_number(), b.as_number()) else {
+        return false;
+    };
+
+    let result = numeric_op(op_code, a, b);
+    stack.pop();
+    *stack.last_mut().unwrap() = result;
+    true
+}
+
+/// The result of the numeric binary instruction `op_code` on `a` and `b`.
+#[inline]
+fn numeric_op(op_code: OpCode, a: f64, b: f64) -> Value {
+    match op_code {
+        OpCode::OpAdd => Value::number(a + b),
+        OpCode::OpSubtract => Value::number(a - b),
+        OpCode::OpMultiply => Value::number(a * b),
+        OpCode::OpDivide => Value::number(a / b),
+        OpCode::OpGreater => Value::bool(a > b),
+        OpCode::OpLess => Value::bool(a < b),
+        OpCode::OpGreaterEqual => Value::bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+        OpCode::OpLessEqual => Value::bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+        _ => unreachable!("{:?} is not a numeric binary instruction", op_code),
     }
+}
+
+#[inline]
+fn read_u16(code: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([code[offset], code[offset + 1]])
+}
 
-    fn binary_op(&mut self, op: BinaryOp) -> InterpretResult {
-        if !self.peek(0).is_number() || !self.peek(1).is_number() {
-            self.runtime_error("Operands must be numbers.");
-            return InterpretResult::RuntimeError;
+/// The value a register code source operand refers to.
+#[inline]
+fn read_operand<'a>(registers: &'a [Value], constants: &'a [Value], operand: u16) -> &'a Value {
+    if operand & CONSTANT == 0 {
+        &registers[operand as usize]
+    } else {
+        &constants[(operand & !CONSTANT) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Position;
+
+    /// `((1 + 2) * -3 - 4) / 2 < 0 == true`, built by hand so that constant
+    /// folding leaves every instruction in place.
+    fn arithmetic_chunk() -> Chunk {
+        let at = Position::default();
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::number(1.0), at);
+        chunk.write_constant(Value::number(2.0), at);
+        chunk.write_chunk(OpCode::OpAdd, at);
+        chunk.write_constant(Value::number(3.0), at);
+        chunk.write_chunk(OpCode::OpNegate, at);
+        chunk.write_chunk(OpCode::OpMultiply, at);
+        chunk.write_constant(Value::number(4.0), at);
+        chunk.write_chunk(OpCode::OpSubtract, at);
+        chunk.write_constant(Value::number(2.0), at);
+        chunk.write_chunk(OpCode::OpDivide, at);
+        chunk.write_constant(Value::number(0.0), at);
+        chunk.write_chunk(OpCode::OpLess, at);
+        chunk.write_chunk(OpCode::OpTrue, at);
+        chunk.write_chunk(OpCode::OpEqual, at);
+        chunk.write_chunk(OpCode::OpReturn, at);
+        chunk
+    }
+
+    /// Run the loaded script to completion `n_instructions` at a time,
+    /// returning its final status and how many calls it took.
+    fn run_in_steps(vm: &mut Vm, n_instructions: usize) -> (RunStatus, usize) {
+        let mut calls = 0;
+        loop {
+            calls += 1;
+            match vm.run_for(n_instructions) {
+                RunStatus::Suspended => {}
+                status => return (status, calls),
+            }
         }
+    }
 
-        let a = self.stack.pop().unwrap();
-        let b = self.stack.pop().unwrap();
-        match a {
-            Value::Number(a) => match b {
-                Value::Number(b) => {
-                    self.stack.push(match op {
-                        BinaryOp::Add => Value::Number(a + b),
-                        BinaryOp::Subtract => Value::Number(a - b),
-                        BinaryOp::Multiply => Value::Number(a * b),
-                        BinaryOp::Divide => Value::Number(a / b),
-                        BinaryOp::Greater => Value::Bool(a < b),
-                        BinaryOp::Less => Value::Bool(a > b),
-                    });
-                    return InterpretResult::Ok;
+    #[test]
+    fn suspended_script_resumes_to_the_same_result() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = Vm::new();
+            vm.set_backend(backend);
+            vm.load_chunk(arithmetic_chunk()).unwrap();
+            let (status, _) = run_in_steps(&mut vm, usize::MAX);
+            let RunStatus::Finished(expected) = status else {
+                panic!("{:?}: uninterrupted run gave {:?}", backend, status);
+            };
+            assert_eq!(expected, Value::bool(true));
+
+            for n_instructions in [1, 2, 3, 5] {
+                vm.load_chunk(arithmetic_chunk()).unwrap();
+                let (status, calls) = run_in_steps(&mut vm, n_instructions);
+                assert!(calls > 1, "{:?}: never suspended", backend);
+                match status {
+                    RunStatus::Finished(value) => assert_eq!(value, expected),
+                    status => panic!("{:?}: stepped run gave {:?}", backend, status),
                 }
-                _ => {}
-            },
-            _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn failed_script_keeps_failing_until_reloaded() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = Vm::new();
+            vm.set_backend(backend);
+            assert!(matches!(vm.load_src("-true + 1"), InterpretResult::Ok));
+
+            let (status, _) = run_in_steps(&mut vm, 1);
+            assert!(matches!(status, RunStatus::RuntimeError), "{:?}", status);
+            for _ in 0..5 {
+                let status = vm.run_for(1);
+                assert!(matches!(status, RunStatus::RuntimeError), "{:?}", status);
+            }
+
+            assert!(matches!(vm.load_src("1 + 2"), InterpretResult::Ok));
+            let (status, _) = run_in_steps(&mut vm, 1);
+            assert!(
+                matches!(&status, RunStatus::Finished(value) if *value == Value::number(3.0)),
+                "{:?}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn finished_script_keeps_its_result_until_reloaded() {
+        let at = Position::default();
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::OpTrue, at);
+        chunk.write_chunk(OpCode::OpReturn, at);
+        chunk.write_chunk(OpCode::OpFalse, at);
+        chunk.write_chunk(OpCode::OpReturn, at);
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = Vm::new();
+            vm.set_backend(backend);
+            vm.load_chunk(chunk.clone()).unwrap();
+
+            let (status, _) = run_in_steps(&mut vm, 1);
+            for status in [status, vm.run_for(1), vm.run_for(usize::MAX)] {
+                assert!(
+                    matches!(&status, RunStatus::Finished(value) if *value == Value::bool(true)),
+                    "{:?}: {:?}",
+                    backend,
+                    status
+                );
+            }
+
+            // Reloading starts the script over rather than replaying the result.
+            vm.load_chunk(chunk.clone()).unwrap();
+            assert!(matches!(vm.run_for(1), RunStatus::Suspended));
+        }
+    }
+
+    #[test]
+    fn compiled_long_constants_run_on_both_backends() {
+        // Every literal operation folds, so only an operand that fails at
+        // runtime keeps 300 distinct constants in the compiled code.
+        let mut source = "-nil".to_string();
+        for n in (0..300).rev() {
+            source = format!("{} == ({})", n, source);
         }
-        InterpretResult::RuntimeError
+        let chunk = Parser::new(&source).compile().unwrap();
+        assert_eq!(chunk.constants.len(), 300);
+        assert!(chunk.code.contains(&(OpCode::OpConstantLong as u8)));
+
+        let mut vm = Vm::new();
+        vm.load_chunk(chunk.clone()).unwrap();
+        assert!(matches!(vm.run_for(usize::MAX), RunStatus::RuntimeError));
+        // Every constant was loaded in order before `-nil` failed.
+        let numbers: Vec<Value> = (0..300).map(|n| Value::number(n as f64)).collect();
+        assert_eq!(vm.stack[..300], numbers);
+        assert_eq!(vm.stack[300..], [Value::nil()]);
+
+        vm.set_backend(Backend::Register);
+        vm.load_chunk(chunk).unwrap();
+        assert!(matches!(vm.run_for(usize::MAX), RunStatus::RuntimeError));
     }
 
-    fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack.len() - distance - 1].clone()
+    /// `left + 2` with `op_code` doing the add, all attributed to 1:3.
+    fn add_chunk(left: Value, op_code: OpCode) -> Chunk {
+        let at = Position {
+            line: 1,
+            column: 3,
+            source_offset: 2,
+        };
+        let mut chunk = Chunk::new();
+        chunk.write_constant(left, at);
+        chunk.write_constant(Value::number(2.0), at);
+        chunk.write_chunk(op_code, at);
+        chunk.write_chunk(OpCode::OpReturn, at);
+        chunk
     }
 
-    fn read_constant(&self, index: usize) -> Value {
-        self.chunk.constants[index].clone()
+    #[test]
+    fn numeric_add_is_quickened() {
+        let mut vm = Vm::new();
+        vm.load_chunk(add_chunk(Value::number(1.0), OpCode::OpAdd))
+            .unwrap();
+        let add = 4;
+        assert_eq!(vm.chunk.code[add], OpCode::OpAdd as u8);
+
+        let status = vm.run_for(usize::MAX);
+        assert!(matches!(&status, RunStatus::Finished(value) if *value == Value::number(3.0)));
+        assert_eq!(vm.chunk.code[add], OpCode::OpAddNumber as u8);
     }
 
-    fn runtime_error(&self, message: &str) {
-        eprintln!("{}", message);
-        let instruction = self.chunk.lines[self.ip - 1].clone();
-        eprintln!("[line {}] in script.", instruction);
+    #[test]
+    fn quickened_add_on_non_numbers_falls_back() {
+        let mut vm = Vm::new();
+        vm.load_chunk(add_chunk(Value::nil(), OpCode::OpAddNumber))
+            .unwrap();
+        let add = 4;
+
+        assert!(matches!(vm.run_for(usize::MAX), RunStatus::RuntimeError));
+        assert_eq!(vm.chunk.code[add], OpCode::OpAdd as u8);
+        assert_eq!(
+            vm.error.as_deref(),
+            Some("<script>:1:3: Error: Operands must be numbers.")
+        );
     }
 }